@@ -43,6 +43,7 @@ fn preamble() -> proc_macro2::TokenStream {
             runtime_path: "::spin_mqtt_sdk::wit_bindgen::rt",
             with: {
                 "spin:mqtt-trigger/spin-mqtt-types": ::spin_mqtt_sdk,
+                "spin:mqtt-trigger/publisher": ::spin_mqtt_sdk::wit::spin::mqtt_trigger::publisher,
             }
         });
         pub struct Mqtt;
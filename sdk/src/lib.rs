@@ -17,4 +17,33 @@ pub mod wit {
 pub use wit_bindgen;
 
 #[doc(inline)]
-pub use wit::spin::mqtt_trigger::spin_mqtt_types::{Error, Metadata, Payload};
+pub use wit::spin::mqtt_trigger::spin_mqtt_types::{Error, Metadata, Payload, PayloadFormat, Qos};
+
+#[doc(inline)]
+pub use wit::spin::mqtt_trigger::publisher::publish;
+
+impl Metadata {
+    /// The level of the topic captured by a named level of the topic pattern,
+    /// e.g. `device` for `site/{site}/device/{device}/telemetry`.
+    pub fn capture(&self, name: &str) -> Option<&str> {
+        self.captures
+            .iter()
+            .find(|(capture, _)| capture == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The level of the topic captured by a named level of the topic pattern,
+    /// parsed as a `T`.
+    pub fn parse_capture<T>(&self, name: &str) -> Result<T, Error>
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        let value = self
+            .capture(name)
+            .ok_or_else(|| Error::Other(format!("no capture named '{name}'")))?;
+        value
+            .parse()
+            .map_err(|e| Error::Other(format!("invalid value '{value}' for capture '{name}': {e}")))
+    }
+}
@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::{Condvar, Mutex},
+};
+use tokio::sync::Notify;
+
+/// What to do with a received message when the receive buffer is full
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Stop reading from the broker until there is room in the buffer
+    #[default]
+    Block,
+    /// Drop the oldest buffered message to make room
+    DropOldest,
+    /// Drop the received message
+    DropNewest,
+}
+
+/// Buffers the messages received from the broker until they are dispatched.
+///
+/// Each trigger subscribed through the connection has a queue of its own, so
+/// that a component which is slow to handle its messages doesn't hold up the
+/// others. The capacity and overflow policy apply to each queue.
+///
+/// paho acknowledges the messages it receives, and the messages the trigger
+/// publishes, on the thread which calls back with the received messages. So
+/// that a handler awaiting the acknowledgement of a message it publishes
+/// doesn't deadlock with a full buffer, the `block` policy lets the queues
+/// exceed their capacity while a publish is awaiting its acknowledgement.
+pub struct ReceiveBuffer {
+    state: Mutex<State>,
+    capacity: Option<NonZeroUsize>,
+    policy: OverflowPolicy,
+    /// Notified when a message is pushed to each queue
+    pushed: Vec<Notify>,
+    /// Notified when a message is popped
+    popped: Condvar,
+    /// Notified when the connection to the broker is lost
+    connection_lost: Notify,
+    /// Called with the queue and the message when a queue is full and a
+    /// message is dropped
+    on_drop: Box<dyn Fn(usize, &paho_mqtt::Message) + Send + Sync>,
+}
+
+struct State {
+    queues: Vec<VecDeque<paho_mqtt::Message>>,
+    /// Whether the queues end once the messages in them are popped
+    draining: bool,
+    /// Whether the buffer no longer accepts messages
+    closed: bool,
+    /// The number of publishes awaiting their acknowledgement
+    publishing: usize,
+}
+
+impl ReceiveBuffer {
+    /// Create a buffer with `queues` queues, each holding up to `capacity`
+    /// messages, or any number if not set.
+    pub fn new(
+        queues: usize,
+        capacity: Option<NonZeroUsize>,
+        policy: OverflowPolicy,
+        on_drop: impl Fn(usize, &paho_mqtt::Message) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            state: Mutex::new(State {
+                queues: (0..queues).map(|_| VecDeque::new()).collect(),
+                draining: false,
+                closed: false,
+                publishing: 0,
+            }),
+            capacity,
+            policy,
+            pushed: (0..queues).map(|_| Notify::new()).collect(),
+            popped: Condvar::new(),
+            connection_lost: Notify::new(),
+            on_drop: Box::new(on_drop),
+        }
+    }
+
+    /// Push a received message to the given queues, applying the overflow
+    /// policy to those which are full.
+    ///
+    /// This is called from paho's callback thread, and blocks it under the
+    /// `block` policy, unless a publish is awaiting its acknowledgement.
+    pub fn push(&self, queues: &[usize], msg: paho_mqtt::Message) {
+        let mut state = self.state.lock().unwrap();
+        if let (Some(capacity), OverflowPolicy::Block) = (self.capacity, self.policy) {
+            while !state.closed
+                && state.publishing == 0
+                && queues
+                    .iter()
+                    .any(|&queue| state.queues[queue].len() >= capacity.get())
+            {
+                state = self.popped.wait(state).unwrap();
+            }
+        }
+        if state.closed {
+            return;
+        }
+        for &queue in queues {
+            let entries = &mut state.queues[queue];
+            if self
+                .capacity
+                .is_some_and(|capacity| entries.len() >= capacity.get())
+            {
+                match self.policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropOldest => {
+                        if let Some(oldest) = entries.pop_front() {
+                            (self.on_drop)(queue, &oldest);
+                        }
+                    }
+                    OverflowPolicy::DropNewest => {
+                        (self.on_drop)(queue, &msg);
+                        continue;
+                    }
+                }
+            }
+            entries.push_back(msg.clone());
+            self.pushed[queue].notify_one();
+        }
+    }
+
+    /// Let the queues exceed their capacity until the returned guard is
+    /// dropped, waking paho's callback thread if it is waiting for room, so
+    /// that it can process the acknowledgement of a message being published.
+    pub fn publishing(&self) -> Publishing<'_> {
+        self.state.lock().unwrap().publishing += 1;
+        self.popped.notify_all();
+        Publishing(self)
+    }
+
+    /// Signal the loss of the connection to the broker.
+    pub fn push_connection_lost(&self) {
+        self.connection_lost.notify_one();
+    }
+
+    /// Wait for the connection to the broker to be lost.
+    pub async fn connection_lost(&self) {
+        self.connection_lost.notified().await;
+    }
+
+    /// End each queue once the messages in it are popped, so that the
+    /// buffered messages are dispatched before shutting down.
+    pub fn drain(&self) {
+        self.state.lock().unwrap().draining = true;
+        for pushed in &self.pushed {
+            pushed.notify_one();
+        }
+    }
+
+    /// Stop accepting messages, and unblock paho's callback thread if it is
+    /// waiting for room in the buffer. Returns the number of messages left
+    /// in the buffer, which are discarded.
+    pub fn close(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.draining = true;
+        state.closed = true;
+        let discarded = state.queues.iter().map(VecDeque::len).sum();
+        state.queues.iter_mut().for_each(VecDeque::clear);
+        self.popped.notify_all();
+        for pushed in &self.pushed {
+            pushed.notify_one();
+        }
+        discarded
+    }
+
+    /// Wait for the next message in a queue, or `None` once the buffer is
+    /// draining and the queue is empty.
+    pub async fn pop(&self, queue: usize) -> Option<paho_mqtt::Message> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(msg) = state.queues[queue].pop_front() {
+                    self.popped.notify_all();
+                    return Some(msg);
+                }
+                if state.draining {
+                    return None;
+                }
+            }
+            self.pushed[queue].notified().await;
+        }
+    }
+}
+
+/// Guards a publish awaiting its acknowledgement; see [`ReceiveBuffer::publishing`].
+pub struct Publishing<'a>(&'a ReceiveBuffer);
+
+impl Drop for Publishing<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().publishing -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn message(topic: &str) -> paho_mqtt::Message {
+        paho_mqtt::Message::new(topic, "", 0)
+    }
+
+    /// A buffer with a single queue, and the topics of the messages it dropped
+    fn buffer(capacity: usize, policy: OverflowPolicy) -> (Arc<ReceiveBuffer>, Dropped) {
+        let dropped = Dropped::default();
+        let buffer = ReceiveBuffer::new(1, NonZeroUsize::new(capacity), policy, {
+            let dropped = dropped.clone();
+            move |_, msg| dropped.lock().unwrap().push(msg.topic().to_owned())
+        });
+        (Arc::new(buffer), dropped)
+    }
+
+    type Dropped = Arc<Mutex<Vec<String>>>;
+
+    async fn topics(buffer: &ReceiveBuffer) -> Vec<String> {
+        buffer.drain();
+        let mut topics = Vec::new();
+        while let Some(msg) = buffer.pop(0).await {
+            topics.push(msg.topic().to_owned());
+        }
+        topics
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room_for_new_messages() {
+        let (buffer, dropped) = buffer(2, OverflowPolicy::DropOldest);
+        for topic in ["a", "b", "c"] {
+            buffer.push(&[0], message(topic));
+        }
+        assert_eq!(topics(&buffer).await, ["b", "c"]);
+        assert_eq!(*dropped.lock().unwrap(), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_buffered_messages() {
+        let (buffer, dropped) = buffer(2, OverflowPolicy::DropNewest);
+        for topic in ["a", "b", "c"] {
+            buffer.push(&[0], message(topic));
+        }
+        assert_eq!(topics(&buffer).await, ["a", "b"]);
+        assert_eq!(*dropped.lock().unwrap(), ["c"]);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (buffer, dropped) = buffer(1, OverflowPolicy::Block);
+        buffer.push(&[0], message("a"));
+        let pusher = std::thread::spawn({
+            let buffer = buffer.clone();
+            move || buffer.push(&[0], message("b"))
+        });
+        assert_eq!(buffer.pop(0).await.unwrap().topic(), "a");
+        pusher.join().unwrap();
+        assert_eq!(topics(&buffer).await, ["b"]);
+        assert!(dropped.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn block_lets_messages_through_while_publishing() {
+        let (buffer, _) = buffer(1, OverflowPolicy::Block);
+        buffer.push(&[0], message("a"));
+        let pusher = std::thread::spawn({
+            let buffer = buffer.clone();
+            move || buffer.push(&[0], message("b"))
+        });
+        let publishing = buffer.publishing();
+        pusher.join().unwrap();
+        drop(publishing);
+        assert_eq!(topics(&buffer).await, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn draining_pops_the_buffered_messages() {
+        let buffer = ReceiveBuffer::new(2, None, OverflowPolicy::Block, |_, _| {});
+        buffer.push(&[0, 1], message("a"));
+        buffer.push(&[0], message("b"));
+        buffer.drain();
+        assert_eq!(buffer.pop(0).await.unwrap().topic(), "a");
+        assert_eq!(buffer.pop(0).await.unwrap().topic(), "b");
+        assert!(buffer.pop(0).await.is_none());
+        assert_eq!(buffer.close(), 1);
+        assert!(buffer.pop(1).await.is_none());
+    }
+}
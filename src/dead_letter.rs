@@ -0,0 +1,116 @@
+use crate::ProtocolVersion;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use paho_mqtt::{MessageBuilder, PropertyCode};
+use serde::Serialize;
+
+/// The dead letter for a message with MQTT 3.1.1, which has no user properties
+/// to carry the failure details.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    /// The topic the message was received on
+    topic: &'a str,
+    /// The error the handler failed with
+    error: &'a str,
+    /// The number of times the handler was called
+    attempts: u32,
+    /// The base64-encoded message payload
+    payload: String,
+}
+
+/// Build the dead letter for a message whose handler failed.
+///
+/// With MQTT v5, the dead letter carries the original payload and user
+/// properties, with the failure details added as `error`, `original-topic` and
+/// `attempts` user properties. With MQTT 3.1.1, it carries a JSON envelope.
+pub fn message(
+    msg: &paho_mqtt::Message,
+    dead_letter_topic: &str,
+    qos: i32,
+    protocol_version: ProtocolVersion,
+    error: &str,
+    attempts: u32,
+) -> anyhow::Result<paho_mqtt::Message> {
+    let builder = MessageBuilder::new().topic(dead_letter_topic).qos(qos);
+    let builder = match protocol_version {
+        ProtocolVersion::V5 => {
+            let mut props = paho_mqtt::Properties::new();
+            for (key, value) in msg.properties().user_iter() {
+                props.push_string_pair(PropertyCode::UserProperty, &key, &value)?;
+            }
+            props.push_string_pair(PropertyCode::UserProperty, "error", error)?;
+            props.push_string_pair(PropertyCode::UserProperty, "original-topic", msg.topic())?;
+            props.push_string_pair(
+                PropertyCode::UserProperty,
+                "attempts",
+                &attempts.to_string(),
+            )?;
+            builder.payload(msg.payload()).properties(props)
+        }
+        ProtocolVersion::V3_1_1 => {
+            let envelope = Envelope {
+                topic: msg.topic(),
+                error,
+                attempts,
+                payload: BASE64_STANDARD.encode(msg.payload()),
+            };
+            builder.payload(serde_json::to_vec(&envelope)?)
+        }
+    };
+    Ok(builder.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received() -> paho_mqtt::Message {
+        let mut props = paho_mqtt::Properties::new();
+        props
+            .push_string_pair(PropertyCode::UserProperty, "source", "sensor")
+            .unwrap();
+        MessageBuilder::new()
+            .topic("site/a")
+            .payload([0x00, 0xff])
+            .qos(1)
+            .properties(props)
+            .finalize()
+    }
+
+    #[test]
+    fn v5_dead_letter_keeps_the_payload_and_adds_the_failure_details() {
+        let msg = received();
+        let dead_letter = message(&msg, "dead/a", 1, ProtocolVersion::V5, "it broke", 3).unwrap();
+        assert_eq!(dead_letter.topic(), "dead/a");
+        assert_eq!(dead_letter.qos(), 1);
+        assert_eq!(dead_letter.payload(), [0x00, 0xff]);
+        let props: Vec<(String, String)> = dead_letter.properties().user_iter().collect();
+        assert_eq!(
+            props,
+            [
+                ("source", "sensor"),
+                ("error", "it broke"),
+                ("original-topic", "site/a"),
+                ("attempts", "3"),
+            ]
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        );
+    }
+
+    #[test]
+    fn v3_dead_letter_is_a_json_envelope_with_a_base64_payload() {
+        let msg = received();
+        let dead_letter =
+            message(&msg, "dead/a", 0, ProtocolVersion::V3_1_1, "it broke", 1).unwrap();
+        assert_eq!(dead_letter.topic(), "dead/a");
+        let envelope: serde_json::Value = serde_json::from_slice(dead_letter.payload()).unwrap();
+        assert_eq!(
+            envelope,
+            serde_json::json!({
+                "topic": "site/a",
+                "error": "it broke",
+                "attempts": 1,
+                "payload": "AP8=",
+            })
+        );
+    }
+}
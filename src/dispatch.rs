@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    num::NonZeroUsize,
+    sync::Arc,
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+};
+
+/// The order in which a component handles messages it runs concurrently
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageOrdering {
+    /// Messages may be handled in any order
+    #[default]
+    None,
+    /// Messages on the same topic are handled in the order they were received
+    PerTopic,
+}
+
+/// Dispatches received messages to a handler, running at most a fixed number
+/// of handlers at once.
+pub struct Dispatcher<H> {
+    handler: Arc<H>,
+    mode: DispatchMode,
+}
+
+enum DispatchMode {
+    /// Each message runs as soon as a permit is available.
+    Unordered {
+        permits: Arc<Semaphore>,
+        max_concurrency: NonZeroUsize,
+    },
+    /// Messages are sharded by topic onto workers which each handle their
+    /// messages one after the other.
+    PerTopic(Vec<(mpsc::Sender<paho_mqtt::Message>, JoinHandle<()>)>),
+}
+
+impl<H, Fut> Dispatcher<H>
+where
+    H: Fn(paho_mqtt::Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    /// Create a dispatcher running up to `max_concurrency` handlers at once.
+    pub fn new(max_concurrency: NonZeroUsize, ordering: MessageOrdering, handler: H) -> Self {
+        let handler = Arc::new(handler);
+        let mode = match ordering {
+            MessageOrdering::None => DispatchMode::Unordered {
+                permits: Arc::new(Semaphore::new(max_concurrency.get())),
+                max_concurrency,
+            },
+            MessageOrdering::PerTopic => {
+                let workers = (0..max_concurrency.get())
+                    .map(|_| {
+                        let (tx, mut rx) = mpsc::channel::<paho_mqtt::Message>(1);
+                        let handler = handler.clone();
+                        let worker = tokio::spawn(async move {
+                            while let Some(msg) = rx.recv().await {
+                                handler(msg).await;
+                            }
+                        });
+                        (tx, worker)
+                    })
+                    .collect();
+                DispatchMode::PerTopic(workers)
+            }
+        };
+        Self { handler, mode }
+    }
+
+    /// Dispatch a message, waiting until there is capacity to handle it.
+    pub async fn dispatch(&self, msg: paho_mqtt::Message) -> anyhow::Result<()> {
+        match &self.mode {
+            DispatchMode::Unordered { permits, .. } => {
+                let permit = permits.clone().acquire_owned().await?;
+                let handler = self.handler.clone();
+                tokio::spawn(async move {
+                    handler(msg).await;
+                    drop(permit);
+                });
+            }
+            DispatchMode::PerTopic(workers) => {
+                let mut hasher = DefaultHasher::new();
+                msg.topic().hash(&mut hasher);
+                let (worker, _) = &workers[hasher.finish() as usize % workers.len()];
+                worker
+                    .send(msg)
+                    .await
+                    .map_err(|_| anyhow::anyhow!("message worker stopped unexpectedly"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for all dispatched messages to be handled.
+    pub async fn drain(self) {
+        match self.mode {
+            DispatchMode::Unordered {
+                permits,
+                max_concurrency,
+            } => {
+                // All permits are available once no handlers are running.
+                let _ = permits.acquire_many(max_concurrency.get() as u32).await;
+            }
+            DispatchMode::PerTopic(workers) => {
+                // Workers stop once their channel is closed and empty.
+                let (senders, handles): (Vec<_>, Vec<_>) = workers.into_iter().unzip();
+                drop(senders);
+                for handle in handles {
+                    let _ = handle.await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn messages_on_a_topic_are_handled_in_order() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let dispatcher =
+            Dispatcher::new(NonZeroUsize::new(4).unwrap(), MessageOrdering::PerTopic, {
+                let handled = handled.clone();
+                move |msg: paho_mqtt::Message| {
+                    let handled = handled.clone();
+                    async move {
+                        let i: u64 = msg.payload_str().parse().unwrap();
+                        // Later messages finish sooner if run concurrently.
+                        tokio::time::sleep(Duration::from_millis(10 - i % 10)).await;
+                        handled.lock().unwrap().push((msg.topic().to_owned(), i));
+                    }
+                }
+            });
+        for i in 0..20 {
+            for topic in ["a", "b", "c"] {
+                let msg = paho_mqtt::Message::new(topic, i.to_string(), 0);
+                dispatcher.dispatch(msg).await.unwrap();
+            }
+        }
+        dispatcher.drain().await;
+
+        let handled = handled.lock().unwrap();
+        assert_eq!(handled.len(), 60);
+        for topic in ["a", "b", "c"] {
+            let order: Vec<u64> = handled
+                .iter()
+                .filter(|(t, _)| t == topic)
+                .map(|(_, i)| *i)
+                .collect();
+            assert_eq!(order, (0..20).collect::<Vec<_>>(), "{topic}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drain_waits_for_the_handlers_in_flight() {
+        for ordering in [MessageOrdering::None, MessageOrdering::PerTopic] {
+            let handled = Arc::new(AtomicUsize::new(0));
+            let dispatcher = Dispatcher::new(NonZeroUsize::new(2).unwrap(), ordering, {
+                let handled = handled.clone();
+                move |_| {
+                    let handled = handled.clone();
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        handled.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+            for topic in ["a", "b", "c"] {
+                let msg = paho_mqtt::Message::new(topic, "", 0);
+                dispatcher.dispatch(msg).await.unwrap();
+            }
+            dispatcher.drain().await;
+            assert_eq!(handled.load(Ordering::SeqCst), 3, "{ordering:?}");
+        }
+    }
+}
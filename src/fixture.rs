@@ -0,0 +1,298 @@
+use crate::mqtt_types;
+use anyhow::Context;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A recorded message to run the components on without a broker.
+///
+/// Fixture files are either `.json` files holding a single message, or
+/// `.jsonl` files holding a message per line, like the files written with
+/// `--record`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    /// The component to run, or all components subscribed to the topic if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub component: Option<String>,
+    /// The topic the message was published to
+    pub topic: String,
+    /// The payload as text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    /// The payload as base64, for binary payloads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_base64: Option<String>,
+    /// The QoS level the message was delivered with
+    #[serde(default)]
+    pub qos: i32,
+    /// Whether the message was retained by the broker
+    #[serde(default)]
+    pub retain: bool,
+    /// MQTT v5 user properties
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_properties: Vec<(String, String)>,
+    /// MQTT v5 content type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// MQTT v5 payload format indicator: 0 for unspecified bytes, 1 for UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_format_indicator: Option<u8>,
+    /// MQTT v5 response topic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<String>,
+    /// MQTT v5 correlation data as base64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_data_base64: Option<String>,
+    /// MQTT v5 message expiry interval, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_expiry_interval: Option<u32>,
+    /// MQTT v5 subscription identifiers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscription_identifiers: Vec<u32>,
+    /// When the message was received, in milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at_ms: Option<u64>,
+}
+
+impl Fixture {
+    /// Record a message received from the broker.
+    pub fn record(msg: &paho_mqtt::Message, received_at_ms: u64) -> Self {
+        let metadata = mqtt_types::Metadata::from(msg);
+        let (payload, payload_base64) = match std::str::from_utf8(msg.payload()) {
+            Ok(payload) => (Some(payload.to_owned()), None),
+            Err(_) => (None, Some(BASE64_STANDARD.encode(msg.payload()))),
+        };
+        Self {
+            component: None,
+            topic: metadata.topic,
+            payload,
+            payload_base64,
+            qos: msg.qos(),
+            retain: msg.retained(),
+            user_properties: metadata.user_properties,
+            content_type: metadata.content_type,
+            payload_format_indicator: metadata.payload_format_indicator.map(|indicator| {
+                match indicator {
+                    mqtt_types::PayloadFormat::Unspecified => 0,
+                    mqtt_types::PayloadFormat::Utf8 => 1,
+                }
+            }),
+            response_topic: metadata.response_topic,
+            correlation_data_base64: metadata
+                .correlation_data
+                .map(|data| BASE64_STANDARD.encode(data)),
+            message_expiry_interval: metadata.message_expiry_interval,
+            subscription_identifiers: metadata.subscription_identifiers,
+            received_at_ms: Some(received_at_ms),
+        }
+    }
+
+    /// The payload of the message.
+    pub fn payload(&self) -> anyhow::Result<Vec<u8>> {
+        match (&self.payload, &self.payload_base64) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one of 'payload' and 'payload_base64' can be set")
+            }
+            (Some(payload), None) => Ok(payload.clone().into_bytes()),
+            (None, Some(payload)) => BASE64_STANDARD
+                .decode(payload)
+                .context("invalid 'payload_base64'"),
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+
+    /// The metadata of the message, before it is routed to a component.
+    pub fn metadata(&self) -> anyhow::Result<mqtt_types::Metadata> {
+        let mut metadata = mqtt_types::Metadata::for_topic(self.topic.clone());
+        metadata.qos = self.qos.into();
+        metadata.retain = self.retain;
+        metadata.user_properties = self.user_properties.clone();
+        metadata.content_type = self.content_type.clone();
+        metadata.payload_format_indicator =
+            self.payload_format_indicator
+                .map(|indicator| match indicator {
+                    1 => mqtt_types::PayloadFormat::Utf8,
+                    _ => mqtt_types::PayloadFormat::Unspecified,
+                });
+        metadata.response_topic = self.response_topic.clone();
+        metadata.correlation_data = self
+            .correlation_data_base64
+            .as_ref()
+            .map(|data| BASE64_STANDARD.decode(data))
+            .transpose()
+            .context("invalid 'correlation_data_base64'")?;
+        metadata.message_expiry_interval = self.message_expiry_interval;
+        metadata.subscription_identifiers = self.subscription_identifiers.clone();
+        Ok(metadata)
+    }
+}
+
+/// Load the fixtures in a directory in file name order, each named after the
+/// file (and line) it was read from.
+pub fn load_dir(dir: &Path) -> anyhow::Result<Vec<(String, Fixture)>> {
+    let mut paths = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read fixtures directory '{}'", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    let mut fixtures = Vec::new();
+    for path in paths {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => {
+                let name = path.display().to_string();
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read fixture '{name}'"))?;
+                let fixture = serde_json::from_str(&contents)
+                    .with_context(|| format!("invalid fixture '{name}'"))?;
+                fixtures.push((name, fixture));
+            }
+            Some("jsonl") => fixtures.extend(load_file(&path)?),
+            _ => {}
+        }
+    }
+    if fixtures.is_empty() {
+        anyhow::bail!("no .json or .jsonl fixtures in '{}'", dir.display());
+    }
+    Ok(fixtures)
+}
+
+/// Load the fixtures in a JSON lines file, each named after the line it was
+/// read from.
+pub fn load_file(path: &Path) -> anyhow::Result<Vec<(String, Fixture)>> {
+    let name = path.display().to_string();
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read fixtures '{name}'"))?;
+    let mut fixtures = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let name = format!("{name}:{}", i + 1);
+        let fixture =
+            serde_json::from_str(line).with_context(|| format!("invalid fixture '{name}'"))?;
+        fixtures.push((name, fixture));
+    }
+    Ok(fixtures)
+}
+
+/// Records the messages received from the broker to a JSON lines file, which
+/// can be replayed with `--replay` or used as fixtures.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Open a file to record to, appending to it if it exists.
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open recording '{}'", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record a message as it is received.
+    pub fn record(&self, msg: &paho_mqtt::Message) -> anyhow::Result<()> {
+        let received_at_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let mut line = serde_json::to_string(&Fixture::record(msg, received_at_ms))?;
+        line.push('\n');
+        self.file.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(json: &str) -> Fixture {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn payload_is_text_or_base64() {
+        let text = fixture(r#"{ "topic": "t", "payload": "hello" }"#);
+        assert_eq!(text.payload().unwrap(), b"hello");
+
+        let binary = fixture(r#"{ "topic": "t", "payload_base64": "AAH/" }"#);
+        assert_eq!(binary.payload().unwrap(), [0x00, 0x01, 0xff]);
+
+        let empty = fixture(r#"{ "topic": "t" }"#);
+        assert!(empty.payload().unwrap().is_empty());
+    }
+
+    #[test]
+    fn payload_rejects_invalid_combinations() {
+        let both = fixture(r#"{ "topic": "t", "payload": "a", "payload_base64": "YQ==" }"#);
+        assert!(both.payload().is_err());
+
+        let invalid = fixture(r#"{ "topic": "t", "payload_base64": "not base64!" }"#);
+        assert!(invalid.payload().is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(serde_json::from_str::<Fixture>(r#"{ "topic": "t", "paylod": "a" }"#).is_err());
+    }
+
+    #[test]
+    fn metadata_carries_delivery_details_and_properties() {
+        let metadata = fixture(
+            r#"{
+                "topic": "site/a",
+                "qos": 2,
+                "retain": true,
+                "user_properties": [["source", "ci"]],
+                "payload_format_indicator": 1,
+                "correlation_data_base64": "AQI=",
+                "subscription_identifiers": [7]
+            }"#,
+        )
+        .metadata()
+        .unwrap();
+        assert_eq!(metadata.topic, "site/a");
+        assert_eq!(metadata.topic_filter, "site/a");
+        assert!(matches!(metadata.qos, mqtt_types::Qos::ExactlyOnce));
+        assert!(metadata.retain);
+        assert_eq!(
+            metadata.user_properties,
+            [("source".to_owned(), "ci".to_owned())]
+        );
+        assert!(matches!(
+            metadata.payload_format_indicator,
+            Some(mqtt_types::PayloadFormat::Utf8)
+        ));
+        assert_eq!(metadata.correlation_data, Some(vec![1, 2]));
+        assert_eq!(metadata.subscription_identifiers, [7]);
+    }
+
+    #[test]
+    fn metadata_rejects_invalid_correlation_data() {
+        let invalid = fixture(r#"{ "topic": "t", "correlation_data_base64": "not base64!" }"#);
+        assert!(invalid.metadata().is_err());
+    }
+
+    #[test]
+    fn recorded_messages_replay_the_same_payload() {
+        let text = Fixture::record(&paho_mqtt::Message::new("t", "hello", 1), 0);
+        assert_eq!(text.payload.as_deref(), Some("hello"));
+        assert_eq!(text.payload().unwrap(), b"hello");
+
+        let binary = Fixture::record(&paho_mqtt::Message::new("t", vec![0xff, 0xfe], 1), 0);
+        assert!(binary.payload.is_none());
+        assert_eq!(binary.payload().unwrap(), [0xff, 0xfe]);
+        assert_eq!(binary.qos, 1);
+        assert_eq!(binary.received_at_ms, Some(0));
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::Context;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most bytes of a request which are read
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// The health of the triggers, as reported by the health endpoint.
+#[derive(Default)]
+pub struct Health {
+    /// The health of each trigger, by trigger id
+    triggers: Mutex<BTreeMap<String, TriggerHealth>>,
+}
+
+#[derive(Clone, Serialize)]
+struct TriggerHealth {
+    /// The component the trigger runs
+    component: String,
+    /// The address of the broker the trigger is subscribed through
+    broker: String,
+    state: ConnectionState,
+    /// When the trigger last received a message, in milliseconds since the Unix epoch
+    last_message_at_ms: Option<u64>,
+    /// The number of messages whose handler failed on every attempt
+    errors: u64,
+    /// The number of messages dropped because the trigger's receive buffer
+    /// was full
+    messages_dropped: u64,
+}
+
+/// The state of the connection a trigger is subscribed through
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionState {
+    /// Connecting to the broker, or subscribing once connected
+    Connecting,
+    /// Connected, with the subscriptions granted by the broker
+    Subscribed,
+    /// The connection was lost, and is being re-established
+    Reconnecting,
+    /// Disconnected on shutdown
+    Disconnected,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    ready: bool,
+    triggers: &'a BTreeMap<String, TriggerHealth>,
+}
+
+impl Health {
+    /// Start tracking a trigger running a component, which connects to the
+    /// broker at `address`.
+    pub fn register(&self, trigger: &str, component: &str, address: &str) {
+        self.triggers.lock().unwrap().insert(
+            trigger.to_owned(),
+            TriggerHealth {
+                component: component.to_owned(),
+                broker: address.to_owned(),
+                state: ConnectionState::Connecting,
+                last_message_at_ms: None,
+                errors: 0,
+                messages_dropped: 0,
+            },
+        );
+    }
+
+    /// Update the state of the connection the triggers are subscribed through.
+    pub fn set_state(&self, triggers: &[String], state: ConnectionState) {
+        let mut health = self.triggers.lock().unwrap();
+        for trigger in triggers {
+            if let Some(trigger) = health.get_mut(trigger) {
+                trigger.state = state;
+            }
+        }
+    }
+
+    /// Record that a trigger received a message.
+    pub fn record_message(&self, trigger: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_millis() as u64)
+            .ok();
+        if let Some(trigger) = self.triggers.lock().unwrap().get_mut(trigger) {
+            trigger.last_message_at_ms = now;
+        }
+    }
+
+    /// Record that a trigger failed to handle a message.
+    pub fn record_error(&self, trigger: &str) {
+        if let Some(trigger) = self.triggers.lock().unwrap().get_mut(trigger) {
+            trigger.errors += 1;
+        }
+    }
+
+    /// Record that a trigger's receive buffer dropped a message, returning
+    /// the number of messages it dropped so far.
+    pub fn record_drop(&self, trigger: &str) -> u64 {
+        match self.triggers.lock().unwrap().get_mut(trigger) {
+            Some(trigger) => {
+                trigger.messages_dropped += 1;
+                trigger.messages_dropped
+            }
+            None => 0,
+        }
+    }
+
+    /// Whether every trigger is subscribed, and the JSON report of the
+    /// health of the triggers.
+    fn report(&self) -> anyhow::Result<(bool, String)> {
+        let triggers = self.triggers.lock().unwrap();
+        // Not ready until the listeners have registered their triggers.
+        let ready = !triggers.is_empty()
+            && triggers
+                .values()
+                .all(|trigger| trigger.state == ConnectionState::Subscribed);
+        let report = serde_json::to_string(&Report {
+            ready,
+            triggers: &triggers,
+        })?;
+        Ok((ready, report))
+    }
+}
+
+/// Bind the health endpoint to an address.
+pub async fn bind(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to listen for health checks on '{addr}'"))
+}
+
+/// Serve the health endpoint: `/healthz` for liveness, which succeeds while
+/// the trigger is running, and `/readyz` for readiness, which succeeds once
+/// every trigger is subscribed. Both report the health of the triggers.
+pub async fn serve(listener: TcpListener, health: Arc<Health>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Failed to accept health check connection: {e}");
+                continue;
+            }
+        };
+        let health = health.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &health).await {
+                tracing::debug!("Failed to respond to health check: {e:?}");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, health: &Health) -> anyhow::Result<()> {
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .context("timed out reading the request")??;
+
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/healthz") => ("200 OK", health.report()?.1),
+        Some("/readyz") => match health.report()? {
+            (true, report) => ("200 OK", report),
+            (false, report) => ("503 Service Unavailable", report),
+        },
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read the head of a request, up to `MAX_REQUEST_SIZE` bytes, returning
+/// its request line.
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut stream = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    // Read the rest of the request head, which isn't needed.
+    let mut line = String::new();
+    while stream.read_line(&mut line).await? > 0 && !line.trim().is_empty() {
+        line.clear();
+    }
+    Ok(request_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ready(health: &Health) -> bool {
+        health.report().unwrap().0
+    }
+
+    #[test]
+    fn not_ready_without_triggers() {
+        assert!(!ready(&Health::default()));
+    }
+
+    #[test]
+    fn ready_once_every_trigger_is_subscribed() {
+        let health = Health::default();
+        health.register("a", "c", "mqtt://localhost:1883");
+        health.register("b", "c", "mqtt://localhost:1884");
+        assert!(!ready(&health));
+
+        health.set_state(&["a".to_owned()], ConnectionState::Subscribed);
+        assert!(!ready(&health));
+
+        health.set_state(&["b".to_owned()], ConnectionState::Subscribed);
+        assert!(ready(&health));
+
+        health.set_state(&["a".to_owned()], ConnectionState::Reconnecting);
+        assert!(!ready(&health));
+    }
+
+    #[test]
+    fn report_lists_triggers_by_id() {
+        let health = Health::default();
+        health.register("a", "c", "mqtt://localhost:1883");
+        health.record_error("a");
+        health.record_drop("a");
+        let (_, report) = health.report().unwrap();
+        let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report["ready"], false);
+        assert_eq!(report["triggers"]["a"]["component"], "c");
+        assert_eq!(report["triggers"]["a"]["state"], "connecting");
+        assert_eq!(report["triggers"]["a"]["errors"], 1);
+        assert_eq!(report["triggers"]["a"]["messages_dropped"], 1);
+    }
+}
@@ -1,12 +1,39 @@
+mod buffer;
+mod dead_letter;
+mod dispatch;
+mod fixture;
+mod health;
+mod pool;
+mod topic;
+mod trace;
+
 use anyhow::{anyhow, Context};
-use clap::Args;
-use paho_mqtt::AsyncClient;
+use buffer::{OverflowPolicy, ReceiveBuffer};
+use clap::{ArgGroup, Args};
+use dispatch::{Dispatcher, MessageOrdering};
+use fixture::{Fixture, Recorder};
+use health::{ConnectionState, Health};
+use paho_mqtt::{AsyncClient, PropertyCode};
+use pool::InstancePool;
 use serde::{Deserialize, Serialize};
 use spin_app::App;
 use spin_factor_variables::VariablesFactor;
 use spin_factors::RuntimeFactors;
-use spin_trigger::{Trigger, TriggerApp};
-use std::{sync::Arc, time::Duration};
+use spin_trigger::{Trigger, TriggerApp, TriggerInstanceState};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    io::Read,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use topic::TopicPattern;
+use tracing::Instrument;
+use wasmtime::component::HasSelf;
 
 // https://docs.rs/wasmtime/latest/wasmtime/component/macro.bindgen.html
 wasmtime::component::bindgen!({
@@ -25,13 +52,39 @@ pub struct MqttTrigger {
     metadata: TriggerMetadata,
     /// Per-component settings
     component_configs: Vec<ComponentConfig>,
-    /// Whether to run in test mode
-    test: bool,
+    /// What to run in test mode, or `None` to connect to the broker
+    test: Option<TestRun>,
+    /// Records the received messages, if recording
+    recorder: Option<Arc<Recorder>>,
+    /// The address to serve the health endpoint on, if any
+    health_listen: Option<SocketAddr>,
+    /// The health of the triggers
+    health: Arc<Health>,
+}
+
+/// A run of the components on messages which don't come from a broker
+#[derive(Clone)]
+struct TestRun {
+    /// The component to run, or all components if not set
+    component: Option<String>,
+    messages: TestMessages,
+}
+
+#[derive(Clone)]
+enum TestMessages {
+    /// A single message, sent to every component whatever its topics
+    Single { topic: String, payload: Vec<u8> },
+    /// Recorded messages, each sent to the components subscribed to its topic
+    Fixtures {
+        fixtures: Vec<(String, Fixture)>,
+        /// Whether to wait between messages as long as between receiving them
+        original_timing: bool,
+    },
 }
 
 impl<F: RuntimeFactors> Trigger<F> for MqttTrigger {
     const TYPE: &'static str = "mqtt";
-    type InstanceState = ();
+    type InstanceState = MqttInstanceState;
     type CliArgs = CliArgs;
 
     fn new(cli_args: Self::CliArgs, app: &App) -> anyhow::Result<Self> {
@@ -39,152 +92,1306 @@ impl<F: RuntimeFactors> Trigger<F> for MqttTrigger {
         let metadata = app
             .get_trigger_metadata::<TriggerMetadata>(trigger_type)?
             .unwrap_or_default();
+        metadata
+            .validate()
+            .context("invalid [application.trigger.mqtt] settings")?;
 
         let component_configs = app
             .trigger_configs::<ComponentConfig>(trigger_type)?
             .into_iter()
-            .map(|(_, config)| config)
-            .collect();
+            .map(|(id, mut config)| {
+                config
+                    .validate()
+                    .with_context(|| format!("invalid [[trigger.mqtt]] '{id}'"))?;
+                config.id = id.to_owned();
+                Ok(config)
+            })
+            .collect::<anyhow::Result<_>>()?;
 
         Ok(Self {
             metadata,
             component_configs,
-            test: cli_args.test,
+            test: cli_args.test_run()?,
+            recorder: cli_args
+                .record
+                .as_deref()
+                .map(Recorder::create)
+                .transpose()?
+                .map(Arc::new),
+            health_listen: cli_args.health_listen,
+            health: Arc::default(),
         })
     }
 
-    async fn run(mut self, trigger_app: TriggerApp<Self, F>) -> anyhow::Result<()> {
-        if self.test {
-            for component in &self.component_configs {
-                self.handle_mqtt_event(
-                    &trigger_app,
-                    &component.component,
-                    b"test message".to_vec(),
-                    "test".to_string(),
-                )
-                .await?;
-            }
+    fn add_to_linker(
+        &mut self,
+        linker: &mut spin_core::Linker<TriggerInstanceState<Self, F>>,
+    ) -> anyhow::Result<()> {
+        spin::mqtt_trigger::publisher::add_to_linker::<_, HasSelf<MqttInstanceState>>(
+            linker,
+            |state| state.executor_instance_state_mut(),
+        )
+    }
 
-            Ok(())
+    async fn run(mut self, trigger_app: TriggerApp<Self, F>) -> anyhow::Result<()> {
+        if let Some(test) = &self.test {
+            self.run_test(&trigger_app, test).await
         } else {
             self.metadata.resolve_variables(&trigger_app).await?;
+
+            // This trigger spawns threads, which Ctrl+C does not kill. So we
+            // detect Ctrl+C (and SIGTERM) and shut the listeners down ourselves.
+            let (shutdown_tx, shutdown) = watch::channel(());
             tokio::spawn(async move {
-                // This trigger spawns threads, which Ctrl+C does not kill. So
-                // for this case we need to detect Ctrl+C and shut those threads
-                // down. For simplicity, we do this by terminating the process.
-                tokio::signal::ctrl_c()
+                shutdown_signal()
                     .await
-                    .expect("failed to listen for Ctrl+C");
-                std::process::exit(0);
+                    .expect("failed to listen for shutdown signals");
+                tracing::info!("Shutting down");
+                let _ = shutdown_tx.send(());
             });
 
+            if let Some(addr) = self.health_listen {
+                let listener = health::bind(addr).await?;
+                tokio::spawn(health::serve(listener, self.health.clone()));
+                tracing::info!("Serving health checks on 'http://{addr}'");
+            }
+
             let trigger = Arc::new(self);
             let trigger_app = Arc::new(trigger_app);
-            let tasks = trigger.component_configs.iter().map(|component_config| {
-                let trigger = trigger.clone();
-                let trigger_app = trigger_app.clone();
-                let component_config = component_config.clone();
-                tokio::spawn(
-                    async move { trigger.run_listener(&trigger_app, component_config).await },
-                )
-            });
-
-            // wait for the first handle to be returned and drop the rest
-            let (result, _, rest) = futures::future::select_all(tasks).await;
 
-            drop(rest);
-            result?
+            // The components connecting to the same broker share a connection.
+            let listeners = trigger
+                .group_by_broker(&trigger_app)
+                .await?
+                .into_iter()
+                .map(|(broker, subscribers)| {
+                    trigger.run_listener(&trigger_app, broker, subscribers, shutdown.clone())
+                });
+            futures::future::try_join_all(listeners).await?;
+            Ok(())
         }
     }
 }
 
 impl MqttTrigger {
-    /// Handle a specific MQTT event
+    /// Run the components on the test messages, failing if any of them fail
+    async fn run_test<F: RuntimeFactors>(
+        &self,
+        trigger_app: &TriggerApp<Self, F>,
+        test: &TestRun,
+    ) -> anyhow::Result<()> {
+        let mut subscribers = Vec::new();
+        for config in &self.component_configs {
+            if test
+                .component
+                .as_ref()
+                .is_some_and(|component| *component != config.component)
+            {
+                continue;
+            }
+            let mut config = config.clone();
+            config.resolve_variables(trigger_app).await?;
+            subscribers.push(Subscriber::new(config)?);
+        }
+        if let Some(component) = &test.component {
+            if subscribers.is_empty() {
+                anyhow::bail!("no mqtt trigger for component '{component}'");
+            }
+        }
+
+        match &test.messages {
+            TestMessages::Single { topic, payload } => {
+                for subscriber in &subscribers {
+                    let mut metadata = mqtt_types::Metadata::for_topic(topic.clone());
+                    subscriber.route(&mut metadata);
+                    self.handle_mqtt_event(
+                        trigger_app,
+                        &subscriber.config.component,
+                        None,
+                        payload.clone(),
+                        metadata,
+                        None,
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+            TestMessages::Fixtures {
+                fixtures,
+                original_timing,
+            } => {
+                let start = tokio::time::Instant::now();
+                let first_received_at_ms = fixtures
+                    .iter()
+                    .find_map(|(_, fixture)| fixture.received_at_ms);
+                let mut failures = 0;
+                for (name, fixture) in fixtures {
+                    if *original_timing {
+                        if let (Some(first), Some(received_at_ms)) =
+                            (first_received_at_ms, fixture.received_at_ms)
+                        {
+                            let offset = received_at_ms.saturating_sub(first);
+                            tokio::time::sleep_until(start + Duration::from_millis(offset)).await;
+                        }
+                    }
+                    let payload = fixture
+                        .payload()
+                        .context(format!("invalid fixture '{name}'"))?;
+                    let targets: Vec<&Subscriber> =
+                        subscribers
+                            .iter()
+                            .filter(|subscriber| {
+                                fixture.component.as_ref().is_none_or(|component| {
+                                    *component == subscriber.config.component
+                                }) && subscriber.matching(&fixture.topic).is_some()
+                            })
+                            .collect();
+                    if targets.is_empty() {
+                        tracing::error!("{name}: no component subscribed to '{}'", fixture.topic);
+                        failures += 1;
+                    }
+                    for subscriber in targets {
+                        let component = &subscriber.config.component;
+                        let mut metadata = fixture
+                            .metadata()
+                            .context(format!("invalid fixture '{name}'"))?;
+                        subscriber.route(&mut metadata);
+                        let result = self
+                            .handle_mqtt_event(
+                                trigger_app,
+                                component,
+                                None,
+                                payload.clone(),
+                                metadata,
+                                None,
+                            )
+                            .await;
+                        match result {
+                            Ok(()) => tracing::info!("{name}: '{component}' succeeded"),
+                            Err(e) => {
+                                tracing::error!("{name}: '{component}' failed: {e:?}");
+                                failures += 1;
+                            }
+                        }
+                    }
+                }
+                if failures > 0 {
+                    anyhow::bail!("{failures} of the fixture messages failed");
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle a specific MQTT event, with a warm instance from the pool if
+    /// one is given
     async fn handle_mqtt_event<F: RuntimeFactors>(
         &self,
         trigger_app: &TriggerApp<Self, F>,
         component_id: &str,
+        connection: Option<Connection>,
         message: Vec<u8>,
-        topic: String,
+        metadata: mqtt_types::Metadata,
+        pool: Option<&InstancePool<WarmInstance<F>>>,
     ) -> anyhow::Result<()> {
+        let span = trace::message_span(component_id, &metadata, message.len());
+        let result = async move {
+            let (instance, mut store) = match pool.and_then(InstancePool::take) {
+                Some(warm) => warm,
+                None => {
+                    let start = Instant::now();
+                    let warm = self
+                        .instantiate(trigger_app, component_id, connection)
+                        .await?;
+                    spin_telemetry::histogram!(
+                        mqtt.instantiation_duration = start.elapsed().as_secs_f64(),
+                        component = component_id
+                    );
+                    warm
+                }
+            };
+
+            let start = Instant::now();
+            let result = instance
+                .call_handle_message(&mut store, &message, &metadata)
+                .await;
+            spin_telemetry::histogram!(
+                mqtt.handler_duration = start.elapsed().as_secs_f64(),
+                component = component_id,
+                topic = metadata.topic_filter.as_str()
+            );
+            // An instance which trapped or failed may be left in an inconsistent
+            // state, so only instances whose handler succeeded are reused.
+            if let (Some(pool), Ok(Ok(()))) = (pool, &result) {
+                pool.put((instance, store));
+            }
+            result?.map_err(|err| anyhow!("failed to execute guest: {err}"))
+        }
+        .instrument(span.clone())
+        .await;
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        result
+    }
+
+    /// Instantiate a component. Spin pre-instantiates the components when
+    /// loading the app, so this only sets up the state of the instance.
+    async fn instantiate<F: RuntimeFactors>(
+        &self,
+        trigger_app: &TriggerApp<Self, F>,
+        component_id: &str,
+        connection: Option<Connection>,
+    ) -> anyhow::Result<WarmInstance<F>> {
         // Load the guest wasm component
         let instance_builder = trigger_app.prepare(component_id)?;
-        let (instance, mut store) = instance_builder.instantiate(()).await?;
+        let (instance, mut store) = instance_builder
+            .instantiate(MqttInstanceState {
+                connection,
+                protocol_version: self.metadata.protocol_version,
+            })
+            .await?;
         // SpinMqtt is auto generated by bindgen as per WIT files referenced above.
         let instance = SpinMqtt::new(&mut store, &instance)?;
+        Ok((instance, store))
+    }
+
+    /// Group the components by the broker connection they use, resolving
+    /// their variables.
+    async fn group_by_broker<F: RuntimeFactors>(
+        &self,
+        trigger_app: &TriggerApp<Self, F>,
+    ) -> anyhow::Result<Vec<(Broker, Vec<Subscriber>)>> {
+        let mut groups: Vec<(Broker, Vec<Subscriber>)> = Vec::new();
+        for mut config in self.component_configs.clone() {
+            config.resolve_variables(trigger_app).await?;
+            let broker = self.broker(&config)?;
+            let subscriber = Subscriber::new(config)?;
+            match groups
+                .iter_mut()
+                .find(|(existing, _)| existing.name == broker.name)
+            {
+                Some((existing, _)) if existing.config != broker.config => {
+                    anyhow::bail!(
+                        "two different broker connections are named '{}': a trigger overriding \
+                         the broker settings cannot have the same id as a named broker",
+                        broker.name.unwrap_or_default()
+                    );
+                }
+                Some((_, subscribers)) => subscribers.push(subscriber),
+                None => groups.push((broker, vec![subscriber])),
+            }
+        }
+        Ok(groups)
+    }
 
-        instance
-            .call_handle_message(store, &message, &mqtt_types::Metadata { topic })
-            .await?
-            .map_err(|err| anyhow!("failed to execute guest: {err}"))
+    /// The broker a component connects to.
+    fn broker(&self, config: &ComponentConfig) -> anyhow::Result<Broker> {
+        let (name, mut broker) = match &config.broker {
+            Some(name) => {
+                let broker = self.metadata.brokers.get(name).with_context(|| {
+                    format!("trigger '{}' references unknown broker '{name}'", config.id)
+                })?;
+                (Some(name.clone()), broker.clone())
+            }
+            None => (None, self.metadata.default_broker()),
+        };
+
+        // A trigger overriding any of the broker settings gets its own connection,
+        // so that a component can bridge brokers through several triggers.
+        if config.has_broker_overrides() {
+            if let Some(address) = &config.address {
+                broker.address = address.clone();
+            }
+            if let Some(username) = &config.username {
+                broker.username = username.clone();
+            }
+            if let Some(password) = &config.password {
+                broker.password = password.clone();
+            }
+            if let Some(tls) = &config.tls {
+                broker.tls = Some(tls.clone());
+            }
+            return Ok(Broker {
+                name: Some(config.id.clone()),
+                config: broker,
+            });
+        }
+
+        Ok(Broker {
+            name,
+            config: broker,
+        })
     }
 
-    /// Run the listener for a specific component
-    async fn run_listener<F: RuntimeFactors>(
+    /// The client ID of the connection to a broker. Each connection needs a
+    /// unique client ID, so the connections to other brokers than the default
+    /// one have the broker name or trigger id appended.
+    fn client_id(&self, broker: &Broker) -> Option<String> {
+        let client_id = self.metadata.client_id.as_ref()?;
+        Some(match &broker.name {
+            Some(name) => format!("{client_id}-{name}"),
+            None => client_id.clone(),
+        })
+    }
+
+    /// Process a message received by a listener, retrying its handler as per
+    /// the retry policy and dead lettering it if all attempts fail
+    async fn process_message<F: RuntimeFactors>(
         &self,
         trigger_app: &TriggerApp<Self, F>,
-        config: ComponentConfig,
+        subscriber: &Subscriber,
+        connection: &Connection,
+        pool: Option<&InstancePool<WarmInstance<F>>>,
+        msg: paho_mqtt::Message,
+    ) {
+        let config = &subscriber.config;
+        let mut metadata = mqtt_types::Metadata::from(&msg);
+        subscriber.route(&mut metadata);
+
+        // Handle the received message, retrying as per the retry policy. Retries
+        // get a fresh instance rather than a warm one from the pool.
+        let mut attempts = 0;
+        let e = loop {
+            attempts += 1;
+            let pool = pool.filter(|_| attempts == 1);
+            let result = self
+                .handle_mqtt_event(
+                    trigger_app,
+                    &config.component,
+                    Some(connection.clone()),
+                    msg.payload().to_vec(),
+                    metadata.clone(),
+                    pool,
+                )
+                .await;
+            match result {
+                Ok(()) => {
+                    spin_telemetry::monotonic_counter!(
+                        mqtt.messages_handled = 1,
+                        component = config.component.as_str(),
+                        topic = metadata.topic_filter.as_str()
+                    );
+                    return;
+                }
+                Err(e) => match &config.retry {
+                    Some(retry) if attempts < retry.max_attempts => {
+                        let delay = retry.delay(attempts);
+                        tracing::warn!(
+                            "Error handling MQTT message (attempt {attempts}), retrying in {delay:?}: {:?}",
+                            e
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    _ => break e,
+                },
+            }
+        };
+        tracing::error!("Error handling MQTT message: {:?}", e);
+        self.health.record_error(&config.id);
+        spin_telemetry::monotonic_counter!(
+            mqtt.messages_failed = 1,
+            component = config.component.as_str(),
+            topic = metadata.topic_filter.as_str()
+        );
+
+        if let Some(dead_letter_topic) = &config.dead_letter_topic {
+            let error = format!("{e:#}");
+            if let Err(e) = self
+                .publish_dead_letter(
+                    connection,
+                    dead_letter_topic,
+                    subscriber.qos,
+                    &msg,
+                    &error,
+                    attempts,
+                )
+                .await
+            {
+                tracing::error!("Error publishing MQTT dead letter: {:?}", e);
+            }
+        }
+    }
+
+    /// Republish a message whose handler failed to a dead letter topic
+    async fn publish_dead_letter(
+        &self,
+        connection: &Connection,
+        dead_letter_topic: &str,
+        qos: i32,
+        msg: &paho_mqtt::Message,
+        error: &str,
+        attempts: u32,
+    ) -> anyhow::Result<()> {
+        let dead_letter = dead_letter::message(
+            msg,
+            dead_letter_topic,
+            qos,
+            self.metadata.protocol_version,
+            error,
+            attempts,
+        )?;
+        connection
+            .publish(dead_letter)
+            .await
+            .context(format!("failed to publish to '{dead_letter_topic}'"))
+    }
+
+    /// Reconnect a client whose connection was lost and restore its subscriptions,
+    /// backing off between attempts as per the reconnect settings. Returns
+    /// `false` if the trigger is shut down before reconnecting.
+    async fn reconnect(
+        &self,
+        broker: &Broker,
+        connection: &Connection,
+        subscriptions: &Subscriptions,
+        shutdown: &mut watch::Receiver<()>,
+    ) -> anyhow::Result<bool> {
+        let settings = &self.metadata.reconnect;
+        let mut attempt = 0;
+        loop {
+            if settings.max_attempts.is_some_and(|max| attempt >= max) {
+                anyhow::bail!(
+                    "failed to reconnect to '{}' after {attempt} attempts",
+                    broker.config.address
+                );
+            }
+
+            let interval = settings.retry_interval(attempt);
+            attempt += 1;
+            tracing::info!(
+                "Reconnecting to '{}' in {interval:?} (attempt {attempt})",
+                broker.config.address
+            );
+            let result = tokio::select! {
+                result = async {
+                    tokio::time::sleep(interval).await;
+                    spin_telemetry::monotonic_counter!(
+                        mqtt.reconnect_attempts = 1,
+                        broker = broker.config.address.as_str()
+                    );
+                    // A previous attempt may have reconnected, but failed to
+                    // subscribe.
+                    if !connection.client.is_connected() {
+                        connection.client.reconnect().await?;
+                    }
+                    // Subscriptions don't survive a clean session, so restore them.
+                    self.subscribe(&connection.client, subscriptions).await
+                } => result,
+                _ = shutdown.changed() => return Ok(false),
+            };
+            match result {
+                Ok(()) => {
+                    spin_telemetry::counter!(
+                        mqtt.connections = 1,
+                        broker = broker.config.address.as_str()
+                    );
+                    tracing::info!("Reconnected to '{}'", broker.config.address);
+                    // The will was published when the connection was lost, so
+                    // announce being back online.
+                    self.announce(connection, self.metadata.birth.as_ref())
+                        .await;
+                    return Ok(true);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to reconnect to '{}': {e:#}", broker.config.address);
+                }
+            }
+        }
+    }
+
+    /// Run the listener for a broker, routing the messages it receives to the
+    /// components subscribed to them
+    async fn run_listener<F: RuntimeFactors>(
+        self: &Arc<Self>,
+        trigger_app: &Arc<TriggerApp<Self, F>>,
+        broker: Broker,
+        subscribers: Vec<Subscriber>,
+        mut shutdown: watch::Receiver<()>,
     ) -> anyhow::Result<()> {
-        let topic = resolve_variables(trigger_app, config.topic).await?;
-
-        // Receive the messages here from the specific topic in mqtt broker.
-        let mut client = AsyncClient::new(self.metadata.address.as_str())?;
-        let keep_alive_interval = self.metadata.keep_alive_interval.parse::<u64>()?;
-        let conn_opts = paho_mqtt::ConnectOptionsBuilder::new()
-            .keep_alive_interval(Duration::from_secs(keep_alive_interval))
-            .user_name(&self.metadata.username)
-            .password(&self.metadata.password)
+        // Receive the messages here from the mqtt broker.
+        let clean_session = self.metadata.clean_session.unwrap_or(true);
+        if !clean_session && self.metadata.client_id.is_none() {
+            anyhow::bail!("'clean_session = false' requires a 'client_id'");
+        }
+        let persistence = match &self.metadata.persistence_dir {
+            Some(dir) if self.metadata.client_id.is_none() => {
+                anyhow::bail!("'persistence_dir' ({dir}) requires a 'client_id'")
+            }
+            Some(dir) => paho_mqtt::PersistenceType::FilePath(dir.into()),
+            None => paho_mqtt::PersistenceType::None,
+        };
+        let create_opts = paho_mqtt::CreateOptionsBuilder::new()
+            .server_uri(broker.config.address.as_str())
+            .client_id(self.client_id(&broker).unwrap_or_default())
+            .mqtt_version(self.metadata.protocol_version.mqtt_version())
+            .persistence(persistence)
             .finalize();
+        let client = AsyncClient::new(create_opts)?;
+        let mut conn_opts = match self.metadata.protocol_version {
+            ProtocolVersion::V3_1_1 => {
+                let mut conn_opts = paho_mqtt::ConnectOptionsBuilder::new();
+                conn_opts.clean_session(clean_session);
+                conn_opts
+            }
+            ProtocolVersion::V5 => {
+                let mut conn_opts = paho_mqtt::ConnectOptionsBuilder::new_v5();
+                conn_opts.clean_start(clean_session);
+                if let Some(interval) = self.metadata.session_expiry_interval {
+                    let mut props = paho_mqtt::Properties::new();
+                    props.push_int(PropertyCode::SessionExpiryInterval, interval as i32)?;
+                    conn_opts.properties(props);
+                }
+                conn_opts
+            }
+        };
+        conn_opts
+            .keep_alive_interval(Duration::from_secs(self.metadata.keep_alive_interval))
+            .user_name(&broker.config.username)
+            .password(&broker.config.password);
+        if let Some(tls) = &broker.config.tls {
+            conn_opts.ssl_options(tls.ssl_options()?);
+        }
+        if let Some(will) = &self.metadata.will {
+            conn_opts.will_message(will.message());
+        }
+        let conn_opts = conn_opts.finalize();
+
+        let subscribers: Vec<Arc<Subscriber>> = subscribers.into_iter().map(Arc::new).collect();
+        let triggers: Vec<String> = subscribers
+            .iter()
+            .map(|subscriber| subscriber.config.id.clone())
+            .collect();
+        for subscriber in &subscribers {
+            let config = &subscriber.config;
+            self.health
+                .register(&config.id, &config.component, &broker.config.address);
+        }
+
+        // Set up the buffer before connecting so that no messages are missed.
+        let buffer = Arc::new(ReceiveBuffer::new(
+            subscribers.len(),
+            self.metadata.receive_buffer_size,
+            self.metadata.overflow_policy,
+            {
+                let health = self.health.clone();
+                let subscribers = subscribers.clone();
+                let address = broker.config.address.clone();
+                move |queue, msg| {
+                    let subscriber = &subscribers[queue];
+                    let trigger = &subscriber.config.id;
+                    let dropped = health.record_drop(trigger);
+                    let topic = subscriber
+                        .matching(msg.topic())
+                        .map(|subscription| subscription.pattern.filter())
+                        .unwrap_or_default();
+                    spin_telemetry::monotonic_counter!(
+                        mqtt.messages_dropped = 1,
+                        broker = address.as_str(),
+                        component = subscriber.config.component.as_str(),
+                        topic = topic
+                    );
+                    tracing::warn!(
+                        "Receive buffer of trigger '{trigger}' full, dropped message on '{}' ({dropped} dropped so far)",
+                        msg.topic()
+                    );
+                }
+            },
+        ));
+        let connection = Connection {
+            client: client.clone(),
+            buffer: buffer.clone(),
+        };
+        let router = Arc::new(Router {
+            subscribers: subscribers.clone(),
+            identifiers: OnceLock::new(),
+        });
+        client.set_message_callback({
+            let buffer = buffer.clone();
+            let router = router.clone();
+            let recorder = self.recorder.clone();
+            let health = self.health.clone();
+            let address = broker.config.address.clone();
+            move |_, msg| {
+                if let Some(msg) = msg {
+                    // Record messages as they arrive, including any the buffer drops.
+                    if let Some(recorder) = &recorder {
+                        if let Err(e) = recorder.record(&msg) {
+                            tracing::warn!("Failed to record message on '{}': {e:?}", msg.topic());
+                        }
+                    }
+                    let triggers = router.routes(&msg);
+                    if triggers.is_empty() {
+                        spin_telemetry::monotonic_counter!(
+                            mqtt.messages_dropped = 1,
+                            broker = address.as_str()
+                        );
+                        tracing::warn!(
+                            "Dropped message on '{}', which matches none of the topic filters subscribed to",
+                            msg.topic()
+                        );
+                        return;
+                    }
+                    for &trigger in &triggers {
+                        let subscriber = &router.subscribers[trigger];
+                        if let Some(subscription) = subscriber.matching(msg.topic()) {
+                            spin_telemetry::monotonic_counter!(
+                                mqtt.messages_received = 1,
+                                component = subscriber.config.component.as_str(),
+                                topic = subscription.pattern.filter()
+                            );
+                        }
+                        health.record_message(&subscriber.config.id);
+                    }
+                    buffer.push(&triggers, msg);
+                }
+            }
+        });
+        client.set_connection_lost_callback({
+            let buffer = buffer.clone();
+            move |_| buffer.push_connection_lost()
+        });
+
+        // Each trigger dispatches the messages in its queue on a task of its
+        // own, so that a trigger at its concurrency limit doesn't hold up the
+        // others.
+        let tasks: Vec<_> = subscribers
+            .iter()
+            .enumerate()
+            .map(|(queue, subscriber)| {
+                let max_concurrency = subscriber
+                    .config
+                    .max_concurrency
+                    .unwrap_or(NonZeroUsize::MIN);
+                // No more instances than can run at once are ever idle.
+                let pool = subscriber
+                    .config
+                    .reuse_instances
+                    .then(|| Arc::new(InstancePool::new(max_concurrency)));
+
+                let dispatcher = {
+                    let trigger = self.clone();
+                    let trigger_app = trigger_app.clone();
+                    let subscriber = subscriber.clone();
+                    let connection = connection.clone();
+                    Dispatcher::new(
+                        max_concurrency,
+                        subscriber.config.ordering,
+                        move |msg: paho_mqtt::Message| {
+                            let trigger = trigger.clone();
+                            let trigger_app = trigger_app.clone();
+                            let subscriber = subscriber.clone();
+                            let connection = connection.clone();
+                            let pool = pool.clone();
+                            async move {
+                                trigger
+                                    .process_message(
+                                        &trigger_app,
+                                        &subscriber,
+                                        &connection,
+                                        pool.as_deref(),
+                                        msg,
+                                    )
+                                    .await
+                            }
+                        },
+                    )
+                };
 
-        client
+                let buffer = buffer.clone();
+                let id = subscriber.config.id.clone();
+                tokio::spawn(async move {
+                    while let Some(msg) = buffer.pop(queue).await {
+                        if let Err(e) = dispatcher.dispatch(msg).await {
+                            tracing::error!("Error dispatching MQTT message to '{id}': {e:?}");
+                            break;
+                        }
+                    }
+                    dispatcher.drain().await;
+                })
+            })
+            .collect();
+
+        let response = client
             .connect(conn_opts)
             .await
-            .context(format!("failed to connect to '{}'", self.metadata.address))?;
-        let qos = config.qos.parse::<i32>()?;
-        client
-            .subscribe(&topic, qos)
-            .await
-            .context(format!("failed to subscribe to '{topic}'"))?;
-
-        // TODO: Should the buffer be bounded/configurable?
-        let rx = client.get_stream(None);
+            .context(format!("failed to connect to '{}'", broker.config.address))?;
+        tracing::info!("Connected to '{}'", broker.config.address);
+        spin_telemetry::counter!(
+            mqtt.connections = 1,
+            broker = broker.config.address.as_str()
+        );
+        // MQTT v5 brokers tell which subscriptions they delivered a message
+        // through, unless they don't support subscription identifiers.
+        let identifiers = matches!(self.metadata.protocol_version, ProtocolVersion::V5)
+            && response
+                .properties()
+                .get_int(PropertyCode::SubscriptionIdentifiersAvailable)
+                != Some(0);
+        let subscriptions = Subscriptions::new(&subscribers, identifiers)?;
+        if identifiers {
+            let _ = router.identifiers.set(
+                subscriptions
+                    .filters
+                    .iter()
+                    .map(|filter| filter.triggers.clone())
+                    .collect(),
+            );
+        }
+        self.subscribe(&client, &subscriptions).await?;
+        self.health
+            .set_state(&triggers, ConnectionState::Subscribed);
+        self.announce(&connection, self.metadata.birth.as_ref())
+            .await;
 
         loop {
-            match rx.recv().await {
-                Ok(Some(msg)) => {
-                    // Handle the received message
-                    if let Err(e) = self
-                        .handle_mqtt_event(
-                            trigger_app,
-                            &config.component,
-                            msg.payload().to_vec(),
-                            msg.topic().to_owned(),
-                        )
-                        .await
-                    {
-                        tracing::error!("Error handling MQTT message: {:?}", e);
+            tokio::select! {
+                _ = buffer.connection_lost() => {
+                    tracing::warn!("Lost connection to '{}'", broker.config.address);
+                    spin_telemetry::counter!(
+                        mqtt.connections = -1,
+                        broker = broker.config.address.as_str()
+                    );
+                    self.health
+                        .set_state(&triggers, ConnectionState::Reconnecting);
+                    if !self.reconnect(&broker, &connection, &subscriptions, &mut shutdown).await? {
+                        break;
                     }
+                    self.health
+                        .set_state(&triggers, ConnectionState::Subscribed);
                 }
-                Ok(None) => {
-                    // Todo: Figure out what this case is
+                _ = shutdown.changed() => break,
+            }
+        }
+
+        // Stop receiving new messages. A persistent session keeps its
+        // subscriptions, so that messages are queued for the trigger while it
+        // is stopped.
+        let connected = client.is_connected();
+        if connected && clean_session {
+            let filters = subscriptions.filters();
+            if let Err(e) = client.unsubscribe_many(&filters).await {
+                tracing::warn!("Failed to unsubscribe from {filters:?}: {e}");
+            }
+        }
+
+        // The messages were acknowledged on receipt, so dispatch those which
+        // are buffered, and give them time to be handled.
+        buffer.drain();
+        let drain_timeout = Duration::from_secs(self.metadata.drain_timeout_secs);
+        let drain = futures::future::join_all(tasks);
+        if tokio::time::timeout(drain_timeout, drain).await.is_err() {
+            tracing::warn!(
+                "Messages were still being handled after {drain_timeout:?}, shutting down anyway"
+            );
+        }
+        let discarded = buffer.close();
+        if discarded > 0 {
+            tracing::warn!("Discarded {discarded} received messages which were not dispatched");
+        }
+
+        self.health
+            .set_state(&triggers, ConnectionState::Disconnected);
+        if !connected {
+            return Ok(());
+        }
+
+        // The broker doesn't publish the will on a clean disconnect, so
+        // announce going offline explicitly.
+        self.announce(&connection, self.metadata.will.as_ref())
+            .await;
+        client.disconnect(None).await.context(format!(
+            "failed to disconnect from '{}'",
+            broker.config.address
+        ))?;
+        tracing::info!("Disconnected from '{}'", broker.config.address);
+        spin_telemetry::counter!(
+            mqtt.connections = -1,
+            broker = broker.config.address.as_str()
+        );
+        Ok(())
+    }
+
+    /// Subscribe to topic filters, failing if the broker rejects any of them.
+    async fn subscribe(
+        &self,
+        client: &AsyncClient,
+        subscriptions: &Subscriptions,
+    ) -> anyhow::Result<()> {
+        if !subscriptions.identifiers {
+            let filters = subscriptions.filters();
+            let qos: Vec<i32> = subscriptions
+                .filters
+                .iter()
+                .map(|filter| filter.qos)
+                .collect();
+            let response = client
+                .subscribe_many(&filters, &qos)
+                .await
+                .context(format!("failed to subscribe to {filters:?}"))?;
+            for (filter, granted) in filters
+                .iter()
+                .zip(response.subscribe_many_response().unwrap_or_default())
+            {
+                check_granted(filter, granted)?;
+            }
+            return Ok(());
+        }
+
+        // A SUBSCRIBE packet carries a single subscription identifier, so each
+        // filter is subscribed to with a packet of its own.
+        for (i, subscribed) in subscriptions.filters.iter().enumerate() {
+            let filter = &subscribed.filter;
+            let mut props = paho_mqtt::Properties::new();
+            props.push_int(PropertyCode::SubscriptionIdentifier, i as i32 + 1)?;
+            let response = client
+                .subscribe_with_options(
+                    filter.as_str(),
+                    subscribed.qos,
+                    paho_mqtt::SubscribeOptions::default(),
+                    props,
+                )
+                .await
+                .context(format!("failed to subscribe to '{filter}'"))?;
+            if let Some(granted) = response.subscribe_response() {
+                check_granted(filter, granted)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish a status message announcing the trigger going online or
+    /// offline. Failing to publish it doesn't stop the trigger handling
+    /// messages, so it is only logged.
+    async fn announce(&self, connection: &Connection, status: Option<&StatusMessageConfig>) {
+        let Some(status) = status else {
+            return;
+        };
+        if let Err(e) = connection.publish(status.message()).await {
+            tracing::warn!("Failed to publish status to '{}': {e}", status.topic);
+        }
+    }
+}
+
+/// A broker the trigger connects to, with its settings resolved
+struct Broker {
+    /// The name of the connection, which is the broker name for a named broker,
+    /// the trigger id for a trigger overriding the broker settings, or `None`
+    /// for the default broker
+    name: Option<String>,
+    config: BrokerConfig,
+}
+
+/// A client connected to a broker, with the buffer of the messages it receives
+#[derive(Clone)]
+struct Connection {
+    client: AsyncClient,
+    buffer: Arc<ReceiveBuffer>,
+}
+
+impl Connection {
+    /// Publish a message. paho's callback thread processes the acknowledgement
+    /// of a QoS 1 or 2 message, so the receive buffer doesn't block it until
+    /// the message is acknowledged.
+    async fn publish(&self, msg: paho_mqtt::Message) -> paho_mqtt::Result<()> {
+        let _publishing = (msg.qos() > 0).then(|| self.buffer.publishing());
+        self.client.publish(msg).await
+    }
+}
+
+/// A component instance, with the store it was instantiated in
+type WarmInstance<F> = (
+    SpinMqtt,
+    spin_core::Store<TriggerInstanceState<MqttTrigger, F>>,
+);
+
+/// Routes the messages received on a connection to the triggers subscribed
+/// through it
+struct Router {
+    subscribers: Vec<Arc<Subscriber>>,
+    /// The triggers subscribed through each subscription identifier, if the
+    /// connection subscribes with identifiers
+    identifiers: OnceLock<Vec<Vec<usize>>>,
+}
+
+impl Router {
+    /// The indices of the triggers a message is routed to.
+    fn routes(&self, msg: &paho_mqtt::Message) -> Vec<usize> {
+        let matching = |trigger: &usize| self.subscribers[*trigger].matching(msg.topic()).is_some();
+        if let Some(identifiers) = self.identifiers.get() {
+            // The message goes to the triggers of the subscriptions it was
+            // delivered through.
+            let mut triggers: Vec<usize> = msg
+                .properties()
+                .iter(PropertyCode::SubscriptionIdentifier)
+                .filter_map(|prop| prop.get_int())
+                .filter_map(|id| identifiers.get(usize::try_from(id).ok()?.checked_sub(1)?))
+                .flatten()
+                .copied()
+                .filter(matching)
+                .collect();
+            triggers.sort_unstable();
+            triggers.dedup();
+            if !triggers.is_empty() {
+                return triggers;
+            }
+        }
+        // Otherwise no two subscriptions overlap, so the broker delivers each
+        // message once and it goes to every trigger subscribed to its topic.
+        (0..self.subscribers.len()).filter(matching).collect()
+    }
+}
+
+/// The topic filters a connection subscribes with
+struct Subscriptions {
+    filters: Vec<SubscribedFilter>,
+    /// Whether each filter is subscribed with a subscription identifier, which
+    /// is its index plus one
+    identifiers: bool,
+}
+
+/// A topic filter a connection subscribes with
+struct SubscribedFilter {
+    /// The share group the filter is subscribed with, if any
+    share_group: Option<String>,
+    /// The topic filter, without the share group
+    topic_filter: String,
+    /// The filter subscribed with, which is a shared subscription filter when
+    /// subscribing with a share group
+    filter: String,
+    /// The highest QoS level any of the triggers subscribed with
+    qos: i32,
+    /// The indices of the triggers subscribed through the filter
+    triggers: Vec<usize>,
+}
+
+impl Subscriptions {
+    /// Plan the subscriptions of the triggers subscribed through a connection.
+    ///
+    /// Each distinct filter is subscribed to once. Without subscription
+    /// identifiers, the broker may deliver a message once for each of the
+    /// subscriptions it matches without telling which, so overlapping filters
+    /// are merged into a single filter matching all of their topics.
+    fn new(subscribers: &[Arc<Subscriber>], identifiers: bool) -> anyhow::Result<Self> {
+        let mut filters: Vec<SubscribedFilter> = Vec::new();
+        for (trigger, subscriber) in subscribers.iter().enumerate() {
+            for subscription in &subscriber.subscriptions {
+                let share_group = &subscription.share_group;
+                let mut topic_filter = subscription.pattern.filter().to_owned();
+                let mut qos = subscription.qos;
+                let mut triggers = vec![trigger];
+                while let Some(i) = filters.iter().position(|existing| {
+                    existing.share_group == *share_group
+                        && if identifiers {
+                            existing.topic_filter == topic_filter
+                        } else {
+                            topic::overlaps(&existing.topic_filter, &topic_filter)
+                        }
+                }) {
+                    let existing = filters.remove(i);
+                    topic_filter = topic::covering(&existing.topic_filter, &topic_filter);
+                    qos = qos.max(existing.qos);
+                    triggers.extend(existing.triggers);
                 }
-                Err(_) => {
-                    // Channel is empty and closed
-                    break;
+                triggers.sort_unstable();
+                triggers.dedup();
+                filters.push(SubscribedFilter {
+                    share_group: share_group.clone(),
+                    filter: match share_group {
+                        Some(group) => topic::shared_filter(group, &topic_filter)?,
+                        None => topic_filter.clone(),
+                    },
+                    topic_filter,
+                    qos,
+                    triggers,
+                });
+            }
+        }
+
+        // Filters in different share groups, or shared and not, are different
+        // subscriptions, so they can't be merged. Subscribing to them without
+        // a share group on every instance of the app would also deliver the
+        // shared messages to all of them.
+        if !identifiers {
+            for (i, a) in filters.iter().enumerate() {
+                if let Some(b) = filters[i + 1..]
+                    .iter()
+                    .find(|b| topic::overlaps(&a.topic_filter, &b.topic_filter))
+                {
+                    anyhow::bail!(
+                        "cannot subscribe to the overlapping topic filters '{}' and '{}' over \
+                         the same connection, as the broker doesn't tell which subscription it \
+                         delivers a message through: use MQTT v5 with a broker supporting \
+                         subscription identifiers, or separate broker connections",
+                        a.filter,
+                        b.filter
+                    );
                 }
             }
         }
+        Ok(Self {
+            filters,
+            identifiers,
+        })
+    }
 
-        Ok(())
+    /// The filters to subscribe with.
+    fn filters(&self) -> Vec<String> {
+        self.filters
+            .iter()
+            .map(|filter| filter.filter.clone())
+            .collect()
+    }
+}
+
+/// A component's subscriptions, with its settings resolved
+struct Subscriber {
+    config: ComponentConfig,
+    /// The QoS level of the component
+    qos: i32,
+    subscriptions: Vec<Subscription>,
+}
+
+/// A topic pattern a component subscribed to
+struct Subscription {
+    pattern: TopicPattern,
+    /// The QoS level the pattern is subscribed with
+    qos: i32,
+    /// The share group the pattern is subscribed with, if any
+    share_group: Option<String>,
+}
+
+impl Subscriber {
+    /// Parse the subscriptions of a component whose variables are resolved.
+    fn new(config: ComponentConfig) -> anyhow::Result<Self> {
+        let qos = config.qos;
+        let mut subscriptions = Vec::new();
+        if let Some(topic) = &config.topic {
+            subscriptions.push((topic, qos));
+        }
+        for topic in &config.topics {
+            subscriptions.push(match topic {
+                TopicConfig::Filter(filter) => (filter, qos),
+                TopicConfig::WithQos(topic) => (&topic.filter, topic.qos),
+            });
+        }
+        if subscriptions.is_empty() {
+            anyhow::bail!(
+                "no 'topic' or 'topics' set for component '{}'",
+                config.component
+            );
+        }
+
+        let subscriptions = subscriptions
+            .into_iter()
+            .map(|(pattern, qos)| {
+                // A shared subscription filter names its share group itself.
+                let (group, filter) = topic::split_shared(pattern)?;
+                let share_group = match (group, &config.share_group) {
+                    (Some(group), Some(share_group)) if group != share_group => anyhow::bail!(
+                        "topic filter '{pattern}' is shared with group '{group}', \
+                         but 'share_group' is '{share_group}'"
+                    ),
+                    (Some(group), _) => Some(group.to_owned()),
+                    (None, share_group) => share_group.clone(),
+                };
+                let pattern = TopicPattern::parse(filter)?;
+                // Check the share group along with the pattern.
+                if let Some(group) = &share_group {
+                    topic::shared_filter(group, pattern.filter())?;
+                }
+                Ok(Subscription {
+                    pattern,
+                    qos,
+                    share_group,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if let Some(dead_letter_topic) = &config.dead_letter_topic {
+            let patterns = subscriptions
+                .iter()
+                .map(|subscription| &subscription.pattern);
+            check_dead_letter_topic(dead_letter_topic, patterns)?;
+        }
+        Ok(Self {
+            config,
+            qos,
+            subscriptions,
+        })
+    }
+
+    /// Fill in the topic filter and captures of the metadata of a message
+    /// routed to the component.
+    fn route(&self, metadata: &mut mqtt_types::Metadata) {
+        if let Some(subscription) = self.matching(&metadata.topic) {
+            metadata.topic_filter = subscription.pattern.filter().to_owned();
+            metadata.captures = subscription.pattern.captures(&metadata.topic);
+        }
+    }
+
+    /// The first of the component's subscriptions which matches a topic, if any.
+    fn matching(&self, topic: &str) -> Option<&Subscription> {
+        self.subscriptions
+            .iter()
+            .find(|subscription| subscription.pattern.matches(topic))
+    }
+}
+
+/// Per-instance state available to the host functions imported by guests
+pub struct MqttInstanceState {
+    /// The connection the message was received on, or `None` in test mode,
+    /// where published messages are only logged
+    connection: Option<Connection>,
+    /// The protocol version of the client
+    protocol_version: ProtocolVersion,
+}
+
+impl spin::mqtt_trigger::publisher::Host for MqttInstanceState {
+    async fn publish(
+        &mut self,
+        topic: String,
+        payload: mqtt_types::Payload,
+        qos: mqtt_types::Qos,
+        retain: bool,
+    ) -> Result<(), mqtt_types::Error> {
+        let Some(connection) = &self.connection else {
+            // Let components which publish run without a broker, as long as
+            // the message could be published to one.
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                return Err(mqtt_types::Error::Other(format!(
+                    "failed to publish to '{topic}': invalid topic name"
+                )));
+            }
+            tracing::info!(
+                "Test mode: not publishing {} bytes to '{topic}' (QoS {}, retain {retain})",
+                payload.len(),
+                i32::from(qos)
+            );
+            return Ok(());
+        };
+
+        let mut message = paho_mqtt::MessageBuilder::new()
+            .topic(&topic)
+            .payload(payload)
+            .qos(qos.into())
+            .retained(retain);
+        // Continue the trace of the message being handled in the consumers of
+        // this one. Only MQTT v5 messages have user properties to carry it.
+        if let ProtocolVersion::V5 = self.protocol_version {
+            let mut props = paho_mqtt::Properties::new();
+            for (key, value) in trace::context_properties() {
+                props
+                    .push_string_pair(PropertyCode::UserProperty, &key, &value)
+                    .map_err(|err| {
+                        mqtt_types::Error::Other(format!("invalid trace context: {err}"))
+                    })?;
+            }
+            message = message.properties(props);
+        }
+        connection.publish(message.finalize()).await.map_err(|err| {
+            mqtt_types::Error::Other(format!("failed to publish to '{topic}': {err}"))
+        })
     }
 }
 
 /// Command line arguments
 #[derive(Args)]
+#[clap(group(ArgGroup::new("test_mode").args(&["test", "fixtures", "replay"])))]
 pub struct CliArgs {
     /// If true, run each component once and exit
     #[clap(long)]
     pub test: bool,
+
+    /// In test mode, only run this component
+    #[clap(long, value_name = "COMPONENT", requires = "test_mode")]
+    pub test_component: Option<String>,
+
+    /// In test mode, the topic of the test message (default `test`)
+    #[clap(long, value_name = "TOPIC", requires = "test")]
+    pub test_topic: Option<String>,
+
+    /// In test mode, the payload of the test message
+    #[clap(
+        long,
+        value_name = "PAYLOAD",
+        requires = "test",
+        conflicts_with = "test_payload_file"
+    )]
+    pub test_payload: Option<String>,
+
+    /// In test mode, read the payload of the test message from a file, or from stdin if `-`
+    #[clap(long, value_name = "PATH", requires = "test")]
+    pub test_payload_file: Option<PathBuf>,
+
+    /// Run the components on the recorded messages in a directory and exit,
+    /// failing if any component fails
+    #[clap(long, value_name = "DIR", conflicts_with = "test")]
+    pub fixtures: Option<PathBuf>,
+
+    /// Serve liveness (`/healthz`) and readiness (`/readyz`) checks over HTTP
+    /// on this address, e.g. `0.0.0.0:8080`
+    #[clap(long, value_name = "ADDRESS")]
+    pub health_listen: Option<SocketAddr>,
+
+    /// Append the messages received from the broker to a JSON lines file
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = &["test", "fixtures", "replay"]
+    )]
+    pub record: Option<PathBuf>,
+
+    /// Run the components on the messages recorded in a file and exit,
+    /// failing if any component fails
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["test", "fixtures"])]
+    pub replay: Option<PathBuf>,
+
+    /// When replaying, wait between messages as long as between receiving
+    /// them, rather than replaying them as fast as possible
+    #[clap(long, requires = "replay")]
+    pub replay_original_timing: bool,
+}
+
+impl CliArgs {
+    /// What to run in test mode, if the trigger runs in test mode.
+    fn test_run(&self) -> anyhow::Result<Option<TestRun>> {
+        let messages = if let Some(dir) = &self.fixtures {
+            TestMessages::Fixtures {
+                fixtures: fixture::load_dir(dir)?,
+                original_timing: false,
+            }
+        } else if let Some(path) = &self.replay {
+            TestMessages::Fixtures {
+                fixtures: fixture::load_file(path)?,
+                original_timing: self.replay_original_timing,
+            }
+        } else if self.test {
+            let payload = match (&self.test_payload, &self.test_payload_file) {
+                (Some(payload), _) => payload.clone().into_bytes(),
+                (None, Some(path)) if path.as_os_str() == "-" => {
+                    let mut payload = Vec::new();
+                    std::io::stdin()
+                        .read_to_end(&mut payload)
+                        .context("failed to read the test payload from stdin")?;
+                    payload
+                }
+                (None, Some(path)) => std::fs::read(path).with_context(|| {
+                    format!("failed to read the test payload from '{}'", path.display())
+                })?,
+                (None, None) => b"test message".to_vec(),
+            };
+            TestMessages::Single {
+                topic: self.test_topic.clone().unwrap_or_else(|| "test".to_owned()),
+                payload,
+            }
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(TestRun {
+            component: self.test_component.clone(),
+            messages,
+        }))
+    }
 }
 
 // Trigger settings (raw serialization format)
@@ -194,7 +1401,54 @@ struct TriggerMetadata {
     address: String,
     username: String,
     password: String,
-    keep_alive_interval: String,
+    /// Seconds between keep alive pings, at most 65535
+    #[serde(deserialize_with = "int_or_string")]
+    keep_alive_interval: u64,
+    /// The MQTT protocol version
+    #[serde(default)]
+    protocol_version: ProtocolVersion,
+    /// The client ID, or one assigned by the broker if not set
+    #[serde(default)]
+    client_id: Option<String>,
+    /// Whether to start a new session on connect, discarding any previous
+    /// session state (default true). Used as the clean start flag with MQTT v5
+    #[serde(default, alias = "clean_start")]
+    clean_session: Option<bool>,
+    /// Seconds the broker keeps the session after disconnecting (MQTT v5 only)
+    #[serde(default)]
+    session_expiry_interval: Option<u32>,
+    /// The directory in which in-flight messages are persisted, or in memory if not set
+    #[serde(default)]
+    persistence_dir: Option<String>,
+    /// The maximum number of received messages waiting to be handled, or unbounded if not set
+    #[serde(default)]
+    receive_buffer_size: Option<NonZeroUsize>,
+    /// What to do with received messages when the receive buffer is full
+    #[serde(default)]
+    overflow_policy: OverflowPolicy,
+    /// The message the broker publishes when the connection is lost, and
+    /// which is published when the trigger shuts down
+    #[serde(default)]
+    will: Option<StatusMessageConfig>,
+    /// The message published when the trigger connects
+    #[serde(default)]
+    birth: Option<StatusMessageConfig>,
+    /// Seconds to wait on shutdown for the messages being handled to finish
+    #[serde(default = "default_drain_timeout_secs")]
+    drain_timeout_secs: u64,
+    /// Reconnection settings, used when the connection to the broker is lost
+    #[serde(default)]
+    reconnect: ReconnectConfig,
+    /// TLS settings, required for brokers with private CAs or client certificates
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    /// Other brokers, which components can connect to by name
+    #[serde(default)]
+    brokers: HashMap<String, BrokerConfig>,
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    10
 }
 
 impl TriggerMetadata {
@@ -209,20 +1463,619 @@ impl TriggerMetadata {
         self.address = address;
         self.username = username;
         self.password = password;
+        if let Some(client_id) = self.client_id.take() {
+            self.client_id = Some(resolve_variables(trigger_app, client_id).await?);
+        }
+        if let Some(persistence_dir) = self.persistence_dir.take() {
+            self.persistence_dir = Some(resolve_variables(trigger_app, persistence_dir).await?);
+        }
+        for status in [&mut self.will, &mut self.birth].into_iter().flatten() {
+            status.resolve_variables(trigger_app).await?;
+        }
+        if let Some(tls) = &mut self.tls {
+            tls.resolve_variables(trigger_app).await?;
+        }
+        for broker in self.brokers.values_mut() {
+            broker.resolve_variables(trigger_app).await?;
+        }
+        Ok(())
+    }
+
+    /// Check the settings which don't depend on variables.
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.keep_alive_interval > u64::from(u16::MAX) {
+            anyhow::bail!(
+                "invalid 'keep_alive_interval' {}: must be between 0 and 65535 seconds",
+                self.keep_alive_interval
+            );
+        }
+        for status in [&self.will, &self.birth].into_iter().flatten() {
+            validate_qos(status.qos)
+                .with_context(|| format!("invalid status message for '{}'", status.topic))?;
+        }
+        Ok(())
+    }
+
+    /// The settings of the broker components connect to by default.
+    fn default_broker(&self) -> BrokerConfig {
+        BrokerConfig {
+            address: self.address.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            tls: self.tls.clone(),
+        }
+    }
+}
+
+// Connection settings of a broker (raw serialization format)
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+struct BrokerConfig {
+    address: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+    /// TLS settings, required for brokers with private CAs or client certificates
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+}
+
+impl BrokerConfig {
+    /// Resolve any variables inside the broker settings.
+    async fn resolve_variables<F: RuntimeFactors>(
+        &mut self,
+        trigger_app: &TriggerApp<MqttTrigger, F>,
+    ) -> anyhow::Result<()> {
+        self.address = resolve_variables(trigger_app, std::mem::take(&mut self.address)).await?;
+        self.username = resolve_variables(trigger_app, std::mem::take(&mut self.username)).await?;
+        self.password = resolve_variables(trigger_app, std::mem::take(&mut self.password)).await?;
+        if let Some(tls) = &mut self.tls {
+            tls.resolve_variables(trigger_app).await?;
+        }
+        Ok(())
+    }
+}
+
+// A message announcing the trigger going online or offline (raw serialization format)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StatusMessageConfig {
+    /// The topic
+    topic: String,
+    /// The payload
+    payload: String,
+    /// The QoS level
+    #[serde(default)]
+    qos: i32,
+    /// Whether the broker retains the message
+    #[serde(default)]
+    retain: bool,
+}
+
+impl StatusMessageConfig {
+    /// Resolve any variables inside the status message.
+    async fn resolve_variables<F: RuntimeFactors>(
+        &mut self,
+        trigger_app: &TriggerApp<MqttTrigger, F>,
+    ) -> anyhow::Result<()> {
+        self.topic = resolve_variables(trigger_app, std::mem::take(&mut self.topic)).await?;
+        self.payload = resolve_variables(trigger_app, std::mem::take(&mut self.payload)).await?;
+        Ok(())
+    }
+
+    /// The status message to publish.
+    fn message(&self) -> paho_mqtt::Message {
+        paho_mqtt::MessageBuilder::new()
+            .topic(&self.topic)
+            .payload(self.payload.as_bytes())
+            .qos(self.qos)
+            .retained(self.retain)
+            .finalize()
+    }
+}
+
+/// The MQTT protocol version used to talk to the broker
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+enum ProtocolVersion {
+    #[default]
+    #[serde(rename = "3.1.1")]
+    V3_1_1,
+    #[serde(rename = "5", alias = "5.0")]
+    V5,
+}
+
+impl ProtocolVersion {
+    /// The paho version constant for this protocol version.
+    fn mqtt_version(self) -> u32 {
+        match self {
+            Self::V3_1_1 => paho_mqtt::MQTT_VERSION_3_1_1,
+            Self::V5 => paho_mqtt::MQTT_VERSION_5,
+        }
+    }
+}
+
+// Reconnection settings (raw serialization format)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReconnectConfig {
+    /// Seconds to wait before the first reconnection attempt
+    min_retry_interval_secs: u64,
+    /// Upper bound in seconds for the exponentially growing wait between attempts
+    max_retry_interval_secs: u64,
+    /// Fraction (between 0 and 1) by which each wait is randomly varied
+    jitter: f64,
+    /// Number of attempts before giving up, or unlimited if not set
+    max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            min_retry_interval_secs: 1,
+            max_retry_interval_secs: 60,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// The wait before the given (zero-based) reconnection attempt.
+    fn retry_interval(&self, attempt: u32) -> Duration {
+        let min = self.min_retry_interval_secs as f64;
+        let max = (self.max_retry_interval_secs as f64).max(min);
+        let interval = (min * 2f64.powi(attempt.min(32) as i32)).min(max);
+        let jitter = interval * self.jitter.clamp(0.0, 1.0) * (2.0 * random_unit() - 1.0);
+        Duration::from_secs_f64((interval + jitter).max(0.0))
+    }
+}
+
+// TLS settings (raw serialization format)
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsConfig {
+    /// Path to a PEM file with the CA certificates used to verify the broker
+    ca_cert: Option<String>,
+    /// Path to a PEM file with the client certificate chain
+    client_cert: Option<String>,
+    /// Path to a PEM file with the client private key
+    client_key: Option<String>,
+    /// Passphrase of the client private key, if it is encrypted
+    client_key_password: Option<String>,
+    /// ALPN protocols to offer during the TLS handshake
+    alpn: Vec<String>,
+    /// If true, don't check that the broker certificate matches its host name
+    skip_hostname_verification: bool,
+}
+
+impl TlsConfig {
+    /// Resolve any variables inside the TLS settings.
+    async fn resolve_variables<F: RuntimeFactors>(
+        &mut self,
+        trigger_app: &TriggerApp<MqttTrigger, F>,
+    ) -> anyhow::Result<()> {
+        for field in [
+            &mut self.ca_cert,
+            &mut self.client_cert,
+            &mut self.client_key,
+            &mut self.client_key_password,
+        ] {
+            if let Some(expr) = field.take() {
+                *field = Some(resolve_variables(trigger_app, expr).await?);
+            }
+        }
+        for protocol in &mut self.alpn {
+            *protocol = resolve_variables(trigger_app, std::mem::take(protocol)).await?;
+        }
         Ok(())
     }
+
+    /// Build the paho SSL options for these settings.
+    fn ssl_options(&self) -> anyhow::Result<paho_mqtt::SslOptions> {
+        if self.client_key.is_some() && self.client_cert.is_none() {
+            anyhow::bail!("TLS 'client_key' requires 'client_cert' to be set");
+        }
+
+        let mut ssl_opts = paho_mqtt::SslOptionsBuilder::new();
+        if let Some(ca_cert) = &self.ca_cert {
+            ssl_opts
+                .trust_store(ca_cert)
+                .context(format!("failed to load CA certificate '{ca_cert}'"))?;
+        }
+        if let Some(client_cert) = &self.client_cert {
+            ssl_opts
+                .key_store(client_cert)
+                .context(format!("failed to load client certificate '{client_cert}'"))?;
+        }
+        if let Some(client_key) = &self.client_key {
+            ssl_opts
+                .private_key(client_key)
+                .context(format!("failed to load client key '{client_key}'"))?;
+        }
+        if let Some(password) = &self.client_key_password {
+            ssl_opts.private_key_password(password);
+        }
+        if !self.alpn.is_empty() {
+            ssl_opts.alpn_protos(&self.alpn);
+        }
+        ssl_opts.verify(!self.skip_hostname_verification);
+        Ok(ssl_opts.finalize())
+    }
 }
 
 // Per-component settings (raw serialization format)
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct ComponentConfig {
+    /// The trigger id, set once the app is loaded
+    #[serde(skip)]
+    id: String,
     /// The component id
     component: String,
-    /// The topic
-    topic: String,
+    /// The topic filter
+    #[serde(default)]
+    topic: Option<String>,
+    /// Multiple topic filters, optionally with their own QoS levels
+    #[serde(default)]
+    topics: Vec<TopicConfig>,
     /// The QoS level
-    qos: String,
+    #[serde(deserialize_with = "int_or_string")]
+    qos: i32,
+    /// The maximum number of messages handled at once (default 1)
+    #[serde(default)]
+    max_concurrency: Option<NonZeroUsize>,
+    /// The order in which messages are handled when handling them concurrently
+    #[serde(default)]
+    ordering: MessageOrdering,
+    /// The topic to republish messages to when their handler fails
+    #[serde(default)]
+    dead_letter_topic: Option<String>,
+    /// The group to share the subscriptions with, so that each message is
+    /// handled by only one of the clients subscribed with the group
+    #[serde(default)]
+    share_group: Option<String>,
+    /// How to retry messages whose handler fails, or not at all if not set
+    #[serde(default)]
+    retry: Option<RetryPolicy>,
+    /// Whether to reuse instances of the component across messages rather
+    /// than instantiating it for each message
+    #[serde(default)]
+    reuse_instances: bool,
+    /// The name of the broker to connect to, or the default broker if not set
+    #[serde(default)]
+    broker: Option<String>,
+    /// Overrides the address of the broker
+    #[serde(default)]
+    address: Option<String>,
+    /// Overrides the username for the broker
+    #[serde(default)]
+    username: Option<String>,
+    /// Overrides the password for the broker
+    #[serde(default)]
+    password: Option<String>,
+    /// Overrides the TLS settings for the broker
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+}
+
+impl ComponentConfig {
+    /// Resolve any variables inside the component settings.
+    async fn resolve_variables<F: RuntimeFactors>(
+        &mut self,
+        trigger_app: &TriggerApp<MqttTrigger, F>,
+    ) -> anyhow::Result<()> {
+        if let Some(topic) = self.topic.take() {
+            self.topic = Some(resolve_variables(trigger_app, topic).await?);
+        }
+        for topic in &mut self.topics {
+            let filter = match topic {
+                TopicConfig::Filter(filter) => filter,
+                TopicConfig::WithQos(config) => &mut config.filter,
+            };
+            *filter = resolve_variables(trigger_app, std::mem::take(filter)).await?;
+        }
+        if let Some(share_group) = self.share_group.take() {
+            self.share_group = Some(resolve_variables(trigger_app, share_group).await?);
+        }
+        if let Some(dead_letter_topic) = self.dead_letter_topic.take() {
+            self.dead_letter_topic = Some(resolve_variables(trigger_app, dead_letter_topic).await?);
+        }
+        for field in [&mut self.address, &mut self.username, &mut self.password] {
+            if let Some(expr) = field.take() {
+                *field = Some(resolve_variables(trigger_app, expr).await?);
+            }
+        }
+        if let Some(tls) = &mut self.tls {
+            tls.resolve_variables(trigger_app).await?;
+        }
+        Ok(())
+    }
+
+    /// Check the settings which don't depend on variables.
+    fn validate(&self) -> anyhow::Result<()> {
+        validate_qos(self.qos)?;
+        if self.topic.is_none() && self.topics.is_empty() {
+            anyhow::bail!("no 'topic' or 'topics' set");
+        }
+        if let Some(topic) = &self.topic {
+            validate_pattern(topic)?;
+        }
+        for topic in &self.topics {
+            match topic {
+                TopicConfig::Filter(filter) => validate_pattern(filter)?,
+                TopicConfig::WithQos(topic) => {
+                    validate_pattern(&topic.filter)?;
+                    validate_qos(topic.qos)
+                        .with_context(|| format!("invalid QoS for '{}'", topic.filter))?;
+                }
+            }
+        }
+        // Topics with variables are only checked once resolved.
+        if let Some(dead_letter_topic) = self
+            .dead_letter_topic
+            .as_deref()
+            .filter(|topic| !topic.contains("{{"))
+        {
+            let patterns = self
+                .topic
+                .iter()
+                .map(String::as_str)
+                .chain(self.topics.iter().map(TopicConfig::filter))
+                .filter(|pattern| !pattern.contains("{{"))
+                .map(|pattern| TopicPattern::parse(topic::split_shared(pattern)?.1))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            check_dead_letter_topic(dead_letter_topic, &patterns)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the component overrides any of the settings of its broker.
+    fn has_broker_overrides(&self) -> bool {
+        self.address.is_some()
+            || self.username.is_some()
+            || self.password.is_some()
+            || self.tls.is_some()
+    }
+}
+
+// A topic filter of a component (raw serialization format)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum TopicConfig {
+    /// A topic filter subscribed with the component's QoS level
+    Filter(String),
+    /// A topic filter with its own QoS level
+    WithQos(TopicQosConfig),
+}
+
+impl TopicConfig {
+    /// The topic filter, which may be a topic pattern
+    fn filter(&self) -> &str {
+        match self {
+            Self::Filter(filter) => filter,
+            Self::WithQos(topic) => &topic.filter,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TopicQosConfig {
+    /// The topic filter
+    filter: String,
+    /// The QoS level
+    #[serde(deserialize_with = "int_or_string")]
+    qos: i32,
+}
+
+// Retry settings for failed handlers (raw serialization format)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct RetryPolicy {
+    /// The maximum number of times the handler is called for a message
+    max_attempts: u32,
+    /// Milliseconds to wait before the first retry
+    initial_delay_ms: u64,
+    /// The factor by which the wait grows with each retry
+    multiplier: f64,
+    /// Upper bound in milliseconds for the wait between retries
+    max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay_ms: 100,
+            multiplier: 2.0,
+            max_delay_ms: 10_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retrying after the given (one-based) failed attempt.
+    fn delay(&self, attempt: u32) -> Duration {
+        let initial = self.initial_delay_ms as f64;
+        let max = (self.max_delay_ms as f64).max(initial);
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(attempt.saturating_sub(1).min(64) as i32);
+        Duration::from_secs_f64((initial * factor).min(max) / 1000.0)
+    }
+}
+
+impl From<mqtt_types::Qos> for i32 {
+    fn from(qos: mqtt_types::Qos) -> Self {
+        match qos {
+            mqtt_types::Qos::AtMostOnce => paho_mqtt::QOS_0,
+            mqtt_types::Qos::AtLeastOnce => paho_mqtt::QOS_1,
+            mqtt_types::Qos::ExactlyOnce => paho_mqtt::QOS_2,
+        }
+    }
+}
+
+impl From<i32> for mqtt_types::Qos {
+    fn from(qos: i32) -> Self {
+        match qos {
+            paho_mqtt::QOS_0 => mqtt_types::Qos::AtMostOnce,
+            paho_mqtt::QOS_1 => mqtt_types::Qos::AtLeastOnce,
+            // Messages are never delivered with a QoS above 2.
+            _ => mqtt_types::Qos::ExactlyOnce,
+        }
+    }
+}
+
+impl mqtt_types::Metadata {
+    /// Metadata for a message which did not come from a broker, e.g. in test mode.
+    fn for_topic(topic: String) -> Self {
+        Self {
+            topic_filter: topic.clone(),
+            topic,
+            captures: Vec::new(),
+            qos: mqtt_types::Qos::AtMostOnce,
+            retain: false,
+            user_properties: Vec::new(),
+            content_type: None,
+            payload_format_indicator: None,
+            response_topic: None,
+            correlation_data: None,
+            message_expiry_interval: None,
+            subscription_identifiers: Vec::new(),
+        }
+    }
+}
+
+impl From<&paho_mqtt::Message> for mqtt_types::Metadata {
+    fn from(msg: &paho_mqtt::Message) -> Self {
+        // MQTT 3.1.1 messages have no properties, so all of these are empty.
+        let props = msg.properties();
+        Self {
+            topic: msg.topic().to_owned(),
+            // Filled in once the message has been routed to a component
+            topic_filter: String::new(),
+            captures: Vec::new(),
+            qos: msg.qos().into(),
+            retain: msg.retained(),
+            user_properties: props.user_iter().collect(),
+            content_type: props.get_string(PropertyCode::ContentType),
+            payload_format_indicator: props.get_int(PropertyCode::PayloadFormatIndicator).map(
+                |indicator| match indicator {
+                    1 => mqtt_types::PayloadFormat::Utf8,
+                    _ => mqtt_types::PayloadFormat::Unspecified,
+                },
+            ),
+            response_topic: props.get_string(PropertyCode::ResponseTopic),
+            correlation_data: props.get_binary(PropertyCode::CorrelationData),
+            message_expiry_interval: props
+                .get_int(PropertyCode::MessageExpiryInterval)
+                .map(|interval| interval as u32),
+            subscription_identifiers: props
+                .iter(PropertyCode::SubscriptionIdentifier)
+                .filter_map(|prop| prop.get_int())
+                .map(|id| id as u32)
+                .collect(),
+        }
+    }
+}
+
+/// Wait for a request to shut down: Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Check that a dead letter topic is a topic name which none of the trigger's
+/// topic patterns match, as the trigger would handle its failed messages again.
+fn check_dead_letter_topic<'a>(
+    topic: &str,
+    patterns: impl IntoIterator<Item = &'a TopicPattern>,
+) -> anyhow::Result<()> {
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        anyhow::bail!(
+            "invalid dead letter topic '{topic}': must be a non-empty topic name without wildcards"
+        );
+    }
+    if let Some(pattern) = patterns.into_iter().find(|pattern| pattern.matches(topic)) {
+        anyhow::bail!(
+            "dead letter topic '{topic}' matches the trigger's topic filter '{}', \
+             so the trigger would handle its failed messages again",
+            pattern.filter()
+        );
+    }
+    Ok(())
+}
+
+/// Check the QoS level the broker granted a subscription, which is 0x80 or
+/// above if it rejected it.
+fn check_granted(filter: &str, granted: i32) -> anyhow::Result<()> {
+    if granted >= 0x80 {
+        anyhow::bail!("the broker rejected the subscription to '{filter}' ({granted:#x})");
+    }
+    Ok(())
+}
+
+/// Check that a QoS level is 0, 1 or 2.
+fn validate_qos(qos: i32) -> anyhow::Result<()> {
+    if !(paho_mqtt::QOS_0..=paho_mqtt::QOS_2).contains(&qos) {
+        anyhow::bail!("invalid QoS level {qos}: must be 0, 1 or 2");
+    }
+    Ok(())
+}
+
+/// Check the syntax of a topic pattern. Patterns with variables are only
+/// checked once the variables are resolved.
+fn validate_pattern(pattern: &str) -> anyhow::Result<()> {
+    if !pattern.contains("{{") {
+        let (group, filter) = topic::split_shared(pattern)?;
+        let pattern = TopicPattern::parse(filter)?;
+        if let Some(group) = group {
+            topic::shared_filter(group, pattern.filter())?;
+        }
+    }
+    Ok(())
+}
+
+/// Deserialize an integer setting, which can also be a string of digits as
+/// these settings were originally strings.
+fn int_or_string<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: TryFrom<i64>,
+    T::Error: std::fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IntOrString {
+        Int(i64),
+        String(String),
+    }
+
+    let value = match IntOrString::deserialize(deserializer)? {
+        IntOrString::Int(value) => value,
+        IntOrString::String(value) => value.trim().parse().map_err(|_| {
+            serde::de::Error::custom(format!("invalid value '{value}': expected an integer"))
+        })?,
+    };
+    T::try_from(value).map_err(|e| serde::de::Error::custom(format!("invalid value {value}: {e}")))
+}
+
+/// A random number in `[0, 1)`, good enough to spread out retries.
+fn random_unit() -> f64 {
+    let random = RandomState::new().hash_one(0u8);
+    (random >> 11) as f64 / (1u64 << 53) as f64
 }
 
 /// Resolve variables in an expression against the variables in the provided trigger app.
@@ -236,3 +2089,250 @@ async fn resolve_variables<F: RuntimeFactors>(
         Err(err) => Err(err.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct IntSetting {
+        #[serde(deserialize_with = "int_or_string")]
+        value: u16,
+    }
+
+    fn int_setting(value: &str) -> anyhow::Result<u16> {
+        let setting: IntSetting = serde_json::from_str(&format!(r#"{{ "value": {value} }}"#))?;
+        Ok(setting.value)
+    }
+
+    fn trigger_metadata(keep_alive_interval: &str) -> TriggerMetadata {
+        serde_json::from_str(&format!(
+            r#"{{
+                "address": "mqtt://localhost:1883",
+                "username": "admin",
+                "password": "public",
+                "keep_alive_interval": {keep_alive_interval}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    fn component_config(topic: &str, qos: &str) -> ComponentConfig {
+        serde_json::from_str(&format!(
+            r#"{{ "component": "c", "topic": "{topic}", "qos": {qos} }}"#
+        ))
+        .unwrap()
+    }
+
+    fn subscriber(topic: &str, qos: &str, share_group: Option<&str>) -> Arc<Subscriber> {
+        let mut config = component_config(topic, qos);
+        config.share_group = share_group.map(str::to_owned);
+        Arc::new(Subscriber::new(config).unwrap())
+    }
+
+    #[test]
+    fn int_settings_accept_integers_and_strings() {
+        assert_eq!(int_setting("30").unwrap(), 30);
+        assert_eq!(int_setting(r#""30""#).unwrap(), 30);
+        assert_eq!(int_setting(r#"" 30 ""#).unwrap(), 30);
+    }
+
+    #[test]
+    fn int_settings_reject_other_values() {
+        for value in [r#""thirty""#, r#""1.5""#, "1.5", "-1", "65536", "true"] {
+            assert!(int_setting(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn qos_must_be_0_1_or_2() {
+        for qos in 0..=2 {
+            assert!(validate_qos(qos).is_ok());
+        }
+        assert!(validate_qos(-1).is_err());
+        assert!(validate_qos(3).is_err());
+    }
+
+    #[test]
+    fn keep_alive_interval_fits_in_16_bits() {
+        assert!(trigger_metadata("0").validate().is_ok());
+        assert!(trigger_metadata(r#""65535""#).validate().is_ok());
+        assert!(trigger_metadata("65536").validate().is_err());
+    }
+
+    #[test]
+    fn component_settings_are_validated() {
+        assert!(component_config("a/+/b", "1").validate().is_ok());
+        assert!(component_config("a/+/b", "3").validate().is_err());
+        assert!(component_config("a/#/b", "1").validate().is_err());
+        assert!(component_config("a/{x}/{x}", "1").validate().is_err());
+        assert!(component_config("$share/g/a/{x}", "1").validate().is_ok());
+        assert!(component_config("$share/g+/a", "1").validate().is_err());
+        assert!(component_config("$queue/a", "1").validate().is_err());
+        let dead_letter = |topic: &str, dead_letter_topic: &str| {
+            let mut config = component_config(topic, "1");
+            config.dead_letter_topic = Some(dead_letter_topic.to_owned());
+            config
+        };
+        assert!(dead_letter("a/+", "dead/a").validate().is_ok());
+        assert!(dead_letter("a/+", "dead/+").validate().is_err());
+        assert!(dead_letter("a/+", "").validate().is_err());
+        assert!(dead_letter("#", "dead/a").validate().is_err());
+        assert!(dead_letter("$share/g/dead/#", "dead/a").validate().is_err());
+        assert!(Subscriber::new(dead_letter("a/{x}", "a/dead")).is_err());
+        // Patterns with variables are only checked once resolved.
+        assert!(component_config("{{ topic }}/#", "1").validate().is_ok());
+    }
+
+    #[test]
+    fn retry_delay_grows_exponentially_up_to_the_maximum() {
+        let retry = RetryPolicy::default();
+        assert_eq!(retry.delay(1), Duration::from_millis(100));
+        assert_eq!(retry.delay(2), Duration::from_millis(200));
+        assert_eq!(retry.delay(3), Duration::from_millis(400));
+        assert_eq!(retry.delay(8), Duration::from_millis(10_000));
+        assert_eq!(retry.delay(u32::MAX), Duration::from_millis(10_000));
+    }
+
+    #[test]
+    fn retry_delay_never_shrinks() {
+        let retry = RetryPolicy {
+            multiplier: 0.5,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(retry.delay(5), Duration::from_millis(100));
+
+        let retry = RetryPolicy {
+            max_delay_ms: 10,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(retry.delay(3), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn overlapping_filters_are_merged_without_identifiers() {
+        let subscribers = [
+            subscriber("a/+", "0", None),
+            subscriber("a/#", "1", None),
+            subscriber("b", "0", None),
+        ];
+        let subscriptions = Subscriptions::new(&subscribers, false).unwrap();
+        assert_eq!(subscriptions.filters(), ["a/#", "b"]);
+        assert_eq!(subscriptions.filters[0].qos, 1);
+        assert_eq!(subscriptions.filters[0].triggers, [0, 1]);
+    }
+
+    #[test]
+    fn identical_filters_are_subscribed_once_with_identifiers() {
+        let subscribers = [
+            subscriber("a/+", "0", None),
+            subscriber("a/#", "1", None),
+            subscriber("a/+", "2", None),
+        ];
+        let subscriptions = Subscriptions::new(&subscribers, true).unwrap();
+        assert_eq!(subscriptions.filters(), ["a/#", "a/+"]);
+        assert_eq!(subscriptions.filters[1].qos, 2);
+        assert_eq!(subscriptions.filters[1].triggers, [0, 2]);
+    }
+
+    #[test]
+    fn shared_filters_are_merged_within_their_group() {
+        let subscribers = [
+            subscriber("a/+", "0", Some("g")),
+            subscriber("a/#", "0", Some("g")),
+            subscriber("b/#", "0", Some("h")),
+        ];
+        let subscriptions = Subscriptions::new(&subscribers, false).unwrap();
+        assert_eq!(subscriptions.filters(), ["$share/g/a/#", "$share/h/b/#"]);
+    }
+
+    #[test]
+    fn filters_overlapping_across_groups_need_identifiers() {
+        let subscribers = [
+            subscriber("a/+", "0", Some("g")),
+            subscriber("a/#", "0", Some("h")),
+        ];
+        assert!(Subscriptions::new(&subscribers, false).is_err());
+        assert!(Subscriptions::new(&subscribers, true).is_ok());
+    }
+
+    #[test]
+    fn messages_are_routed_by_subscription_identifier() {
+        let router = Router {
+            subscribers: vec![subscriber("a/+", "0", None), subscriber("a/#", "0", None)],
+            identifiers: OnceLock::from(vec![vec![0], vec![1]]),
+        };
+        let mut props = paho_mqtt::Properties::new();
+        props
+            .push_int(PropertyCode::SubscriptionIdentifier, 2)
+            .unwrap();
+        let msg = paho_mqtt::MessageBuilder::new()
+            .topic("a/b")
+            .properties(props)
+            .finalize();
+        assert_eq!(router.routes(&msg), [1]);
+        // Messages without identifiers go to every trigger subscribed to their topic.
+        assert_eq!(
+            router.routes(&paho_mqtt::Message::new("a/b", "", 0)),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn shared_filters_name_their_share_group() {
+        let subscribers = [subscriber("$share/g/a/+", "0", None)];
+        let subscriptions = Subscriptions::new(&subscribers, false).unwrap();
+        assert_eq!(subscriptions.filters(), ["$share/g/a/+"]);
+
+        let router = Router {
+            subscribers: subscribers.to_vec(),
+            identifiers: OnceLock::new(),
+        };
+        assert_eq!(router.routes(&paho_mqtt::Message::new("a/b", "", 0)), [0]);
+
+        let mut config = component_config("$share/g/a/+", "0");
+        config.share_group = Some("h".to_owned());
+        assert!(Subscriber::new(config).is_err());
+    }
+
+    #[test]
+    fn shared_filters_overlapping_unshared_ones_need_identifiers() {
+        let subscribers = |shared: &str, unshared: &str| {
+            [
+                subscriber(shared, "0", Some("g")),
+                subscriber(unshared, "0", None),
+            ]
+        };
+        assert!(Subscriptions::new(&subscribers("a/+", "a/#"), false).is_err());
+        assert!(Subscriptions::new(&subscribers("a/+", "a/#"), true).is_ok());
+        assert!(Subscriptions::new(&subscribers("a/+", "b/#"), false).is_ok());
+        assert!(Subscriptions::new(&subscribers("$SYS/#", "#"), false).is_ok());
+    }
+
+    #[test]
+    fn test_message_flags_require_test_mode() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            args: CliArgs,
+        }
+        let parse = |args: &[&str]| {
+            <Cli as clap::Parser>::try_parse_from(
+                std::iter::once("trigger").chain(args.iter().copied()),
+            )
+            .map(|cli| cli.args)
+        };
+        assert!(parse(&["--test", "--test-topic", "a", "--test-payload", "b"]).is_ok());
+        assert!(parse(&["--fixtures", "dir", "--test-component", "c"]).is_ok());
+        assert!(parse(&["--replay", "capture.jsonl", "--test-component", "c"]).is_ok());
+        for flag in [
+            "--test-component",
+            "--test-topic",
+            "--test-payload",
+            "--test-payload-file",
+        ] {
+            assert!(parse(&[flag, "a"]).is_err(), "{flag}");
+        }
+        assert!(parse(&["--fixtures", "dir", "--test-topic", "a"]).is_err());
+    }
+}
@@ -0,0 +1,32 @@
+use std::{num::NonZeroUsize, sync::Mutex};
+
+/// A bounded pool of warm component instances, reused across messages
+/// instead of instantiating the component for each one.
+pub struct InstancePool<T> {
+    instances: Mutex<Vec<T>>,
+    capacity: NonZeroUsize,
+}
+
+impl<T> InstancePool<T> {
+    /// Create a pool keeping up to `capacity` idle instances.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            instances: Mutex::new(Vec::with_capacity(capacity.get())),
+            capacity,
+        }
+    }
+
+    /// Take an idle instance, if there is one.
+    pub fn take(&self) -> Option<T> {
+        self.instances.lock().unwrap().pop()
+    }
+
+    /// Return an instance to the pool once it is idle, dropping it if the
+    /// pool is full.
+    pub fn put(&self, instance: T) {
+        let mut instances = self.instances.lock().unwrap();
+        if instances.len() < self.capacity.get() {
+            instances.push(instance);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+/// Whether a topic name matches a topic filter, which may contain the `+`
+/// (single level) and `#` (multi level) wildcards.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    // Topics starting with `$` are not matched by filters starting with a wildcard.
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            // `#` also matches the parent level, e.g. `a/#` matches `a`
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Whether some topic matches both of two topic filters.
+pub fn overlaps(a: &str, b: &str) -> bool {
+    // Topics starting with `$` are not matched by filters starting with a wildcard.
+    let wildcard = |filter: &str| filter.starts_with(['+', '#']);
+    if (a.starts_with('$') && wildcard(b)) || (b.starts_with('$') && wildcard(a)) {
+        return false;
+    }
+
+    let mut a_levels = a.split('/');
+    let mut b_levels = b.split('/');
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some("+"), Some(_)) | (Some(_), Some("+")) => {}
+            (Some(a_level), Some(b_level)) if a_level == b_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// A topic filter matching every topic either of two overlapping topic
+/// filters matches, keeping the levels they have in common, e.g. `a/+/+` for
+/// `a/b/+` and `a/+/c`.
+pub fn covering(a: &str, b: &str) -> String {
+    let mut a_levels = a.split('/');
+    let mut b_levels = b.split('/');
+    let mut levels = Vec::new();
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (None, None) => break,
+            (Some(a_level), Some(b_level)) if a_level == b_level => levels.push(a_level),
+            (Some("#"), _) | (_, Some("#")) | (None, _) | (_, None) => {
+                levels.push("#");
+                break;
+            }
+            (Some(_), Some(_)) => levels.push("+"),
+        }
+    }
+    levels.join("/")
+}
+
+/// Check that a topic filter is well formed: the `+` and `#` wildcards must
+/// each occupy a whole level, and `#` must be the last level.
+pub fn validate_filter(filter: &str) -> anyhow::Result<()> {
+    if filter.is_empty() {
+        anyhow::bail!("topic filter cannot be empty");
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().copied().enumerate() {
+        match level {
+            "+" => {}
+            "#" if i + 1 == levels.len() => {}
+            "#" => anyhow::bail!("'#' must be the last level of topic filter '{filter}'"),
+            level if level.contains(['+', '#']) => {
+                anyhow::bail!("wildcards must occupy a whole level of topic filter '{filter}'")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A topic filter whose wildcard levels may be named, to capture the levels
+/// of the topics it matches, e.g. `site/{site}/device/{device}/telemetry`.
+///
+/// `{name}` matches a single level like `+`, and `{*name}` matches any number
+/// of levels like `#`.
+#[derive(Clone, Debug)]
+pub struct TopicPattern {
+    /// The equivalent MQTT topic filter
+    filter: String,
+    /// The named levels
+    captures: Vec<Capture>,
+}
+
+#[derive(Clone, Debug)]
+struct Capture {
+    name: String,
+    /// The index of the captured level
+    level: usize,
+    /// Whether the remaining levels are captured
+    multi_level: bool,
+}
+
+impl TopicPattern {
+    /// Parse a pattern, which can also be a plain topic filter.
+    pub fn parse(pattern: &str) -> anyhow::Result<Self> {
+        let segments: Vec<&str> = pattern.split('/').collect();
+        let mut levels = Vec::with_capacity(segments.len());
+        let mut captures = Vec::new();
+        for (level, segment) in segments.iter().copied().enumerate() {
+            let Some(name) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) else {
+                levels.push(segment);
+                continue;
+            };
+            let (name, multi_level) = match name.strip_prefix('*') {
+                Some(name) => (name, true),
+                None => (name, false),
+            };
+            if name.is_empty() {
+                anyhow::bail!("unnamed capture in topic pattern '{pattern}'");
+            }
+            if multi_level && level + 1 != segments.len() {
+                anyhow::bail!(
+                    "capture '{{*{name}}}' must be the last level of topic pattern '{pattern}'"
+                );
+            }
+            if captures
+                .iter()
+                .any(|capture: &Capture| capture.name == name)
+            {
+                anyhow::bail!("duplicate capture '{name}' in topic pattern '{pattern}'");
+            }
+            levels.push(if multi_level { "#" } else { "+" });
+            captures.push(Capture {
+                name: name.to_owned(),
+                level,
+                multi_level,
+            });
+        }
+        let filter = levels.join("/");
+        validate_filter(&filter)?;
+        Ok(Self { filter, captures })
+    }
+
+    /// The MQTT topic filter to subscribe with.
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    /// Whether a topic matches the pattern.
+    pub fn matches(&self, topic: &str) -> bool {
+        matches(&self.filter, topic)
+    }
+
+    /// The values of the named levels in a topic matching the pattern.
+    pub fn captures(&self, topic: &str) -> Vec<(String, String)> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        self.captures
+            .iter()
+            .map(|capture| {
+                let value = match levels.get(capture.level..) {
+                    Some(rest) if capture.multi_level => rest.join("/"),
+                    Some(rest) => rest.first().copied().unwrap_or_default().to_owned(),
+                    // `a/#` also matches `a`, capturing nothing
+                    None => String::new(),
+                };
+                (capture.name.clone(), value)
+            })
+            .collect()
+    }
+}
+
+/// The filter for a shared subscription to a topic filter, which the broker
+/// balances across all clients subscribed with the same group.
+pub fn shared_filter(group: &str, filter: &str) -> anyhow::Result<String> {
+    if group.is_empty() || group.contains(['/', '+', '#']) {
+        anyhow::bail!(
+            "invalid share group '{group}': must be non-empty and not contain '/', '+' or '#'"
+        );
+    }
+    if filter.is_empty() || filter.starts_with("$share/") {
+        anyhow::bail!("topic filter '{filter}' cannot be used in a shared subscription");
+    }
+    Ok(format!("$share/{group}/{filter}"))
+}
+
+/// Split a shared subscription filter (`$share/<group>/<filter>`) into its
+/// share group and topic filter. Other filters have no share group.
+pub fn split_shared(filter: &str) -> anyhow::Result<(Option<&str>, &str)> {
+    if filter.starts_with("$queue/") {
+        anyhow::bail!(
+            "topic filter '{filter}' is a broker-specific shared subscription: set 'share_group' instead"
+        );
+    }
+    let Some(shared) = filter.strip_prefix("$share/") else {
+        return Ok((None, filter));
+    };
+    match shared.split_once('/') {
+        Some((group, topic_filter)) if !topic_filter.is_empty() => Ok((Some(group), topic_filter)),
+        _ => anyhow::bail!(
+            "invalid shared subscription '{filter}': expected '$share/<group>/<filter>'"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_levels() {
+        assert!(matches("a/+/c", "a/b/c"));
+        assert!(!matches("a/+/c", "a/b/d"));
+        assert!(!matches("a/+", "a/b/c"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(matches("#", "a/b/c"));
+        assert!(!matches("a/b", "a/b/c"));
+        assert!(!matches("a/b/c", "a/b"));
+    }
+
+    #[test]
+    fn multi_level_wildcard_matches_parent() {
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/+/#", "a/b"));
+        assert!(!matches("a/+/#", "a"));
+    }
+
+    #[test]
+    fn empty_levels_are_levels() {
+        assert!(matches("a//c", "a//c"));
+        assert!(matches("a/+/c", "a//c"));
+        assert!(matches("+/a", "/a"));
+        assert!(matches("a/+", "a/"));
+        assert!(!matches("a", "a/"));
+    }
+
+    #[test]
+    fn leading_wildcards_dont_match_dollar_topics() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn overlapping_filters_match_a_common_topic() {
+        for (a, b) in [
+            ("a/b", "a/b"),
+            ("a/+", "a/b"),
+            ("a/#", "a/b/c"),
+            ("a/#", "a"),
+            ("a/b/+", "a/+/c"),
+            ("+/x", "y/+"),
+            ("#", "a"),
+            ("$SYS/#", "$SYS/+/uptime"),
+        ] {
+            assert!(overlaps(a, b), "{a} {b}");
+            assert!(overlaps(b, a), "{b} {a}");
+        }
+    }
+
+    #[test]
+    fn disjoint_filters_match_no_common_topic() {
+        for (a, b) in [
+            ("a/b", "a/c"),
+            ("a", "a/b"),
+            ("a/+", "a"),
+            ("a/+/c", "a/+/d"),
+            ("#", "$SYS/uptime"),
+            ("+/uptime", "$SYS/uptime"),
+        ] {
+            assert!(!overlaps(a, b), "{a} {b}");
+            assert!(!overlaps(b, a), "{b} {a}");
+        }
+    }
+
+    #[test]
+    fn covering_filter_matches_both_filters() {
+        assert_eq!(covering("a/b", "a/b"), "a/b");
+        assert_eq!(covering("a/+", "a/b"), "a/+");
+        assert_eq!(covering("a/#", "a/b/c"), "a/#");
+        assert_eq!(covering("a/b/+", "a/+/c"), "a/+/+");
+        assert_eq!(covering("a", "a/#"), "a/#");
+        assert_eq!(covering("a/b", "a/b/c"), "a/b/#");
+    }
+
+    #[test]
+    fn validate_filter_accepts_whole_level_wildcards() {
+        for filter in ["a", "a/b", "+", "#", "+/+/#", "a//b", "/a", "$SYS/#"] {
+            assert!(validate_filter(filter).is_ok(), "{filter}");
+        }
+    }
+
+    #[test]
+    fn validate_filter_rejects_malformed_filters() {
+        for filter in ["", "a/#/b", "#/a", "a#", "a/b+", "a/+b/c", "a/##"] {
+            assert!(validate_filter(filter).is_err(), "{filter}");
+        }
+    }
+
+    #[test]
+    fn pattern_captures_named_levels() {
+        let pattern = TopicPattern::parse("site/{site}/device/{device}/telemetry").unwrap();
+        assert_eq!(pattern.filter(), "site/+/device/+/telemetry");
+        assert!(pattern.matches("site/s1/device/d2/telemetry"));
+        assert_eq!(
+            pattern.captures("site/s1/device/d2/telemetry"),
+            [
+                ("site".to_owned(), "s1".to_owned()),
+                ("device".to_owned(), "d2".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn pattern_captures_remaining_levels() {
+        let pattern = TopicPattern::parse("logs/{app}/{*path}").unwrap();
+        assert_eq!(pattern.filter(), "logs/+/#");
+        assert_eq!(
+            pattern.captures("logs/web/a/b/c"),
+            [
+                ("app".to_owned(), "web".to_owned()),
+                ("path".to_owned(), "a/b/c".to_owned())
+            ]
+        );
+        // `logs/+/#` also matches the parent level, capturing nothing.
+        assert_eq!(
+            pattern.captures("logs/web"),
+            [
+                ("app".to_owned(), "web".to_owned()),
+                ("path".to_owned(), String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn pattern_without_captures_is_a_filter() {
+        let pattern = TopicPattern::parse("a/+/#").unwrap();
+        assert_eq!(pattern.filter(), "a/+/#");
+        assert!(pattern.captures("a/b/c").is_empty());
+    }
+
+    #[test]
+    fn pattern_rejects_invalid_captures() {
+        for pattern in [
+            "{*rest}/a",
+            "a/{*rest}/b",
+            "a/{x}/{x}",
+            "a/{x}/{*x}",
+            "a/{}",
+            "a/{*}",
+            "a/#/{x}",
+            "",
+        ] {
+            assert!(TopicPattern::parse(pattern).is_err(), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn shared_filter_prefixes_group() {
+        assert_eq!(shared_filter("g", "a/+").unwrap(), "$share/g/a/+");
+        assert_eq!(shared_filter("g", "#").unwrap(), "$share/g/#");
+    }
+
+    #[test]
+    fn shared_filter_rejects_invalid_groups_and_filters() {
+        for group in ["", "a/b", "g+", "g#"] {
+            assert!(shared_filter(group, "a").is_err(), "{group}");
+        }
+        assert!(shared_filter("g", "").is_err());
+        assert!(shared_filter("g", "$share/h/a").is_err());
+    }
+
+    #[test]
+    fn split_shared_separates_the_share_group() {
+        assert_eq!(split_shared("$share/g/a/+").unwrap(), (Some("g"), "a/+"));
+        assert_eq!(split_shared("$share/g/#").unwrap(), (Some("g"), "#"));
+        assert_eq!(split_shared("a/+").unwrap(), (None, "a/+"));
+        assert_eq!(split_shared("$SYS/#").unwrap(), (None, "$SYS/#"));
+    }
+
+    #[test]
+    fn split_shared_rejects_incomplete_and_queue_filters() {
+        for filter in ["$share/g", "$share/g/", "$queue/a"] {
+            assert!(split_shared(filter).is_err(), "{filter}");
+        }
+    }
+}
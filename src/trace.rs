@@ -0,0 +1,76 @@
+use crate::mqtt_types;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TraceContextExt,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The span of the handling of a message by a component.
+///
+/// If the message carries a W3C trace context (`traceparent` and `tracestate`)
+/// in its MQTT v5 user properties, the span continues that trace.
+pub fn message_span(
+    component_id: &str,
+    metadata: &mqtt_types::Metadata,
+    payload_size: usize,
+) -> tracing::Span {
+    let name = format!("process {}", metadata.topic_filter);
+    let span = tracing::info_span!(
+        "mqtt process",
+        "otel.kind" = "consumer",
+        "otel.name" = name.as_str(),
+        "otel.status_code" = tracing::field::Empty,
+        "messaging.system" = "mqtt",
+        "messaging.operation.type" = "process",
+        "messaging.destination.name" = metadata.topic.as_str(),
+        "messaging.destination.template" = metadata.topic_filter.as_str(),
+        "messaging.message.body.size" = payload_size,
+        "messaging.mqtt.qos" = i32::from(metadata.qos),
+        "spin.component_id" = component_id,
+    );
+
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&UserProperties(&metadata.user_properties))
+    });
+    if parent.span().span_context().is_valid() {
+        span.set_parent(parent);
+    }
+    span
+}
+
+/// The user properties carrying the trace context of the current span, so
+/// that the consumers of a published message continue the trace.
+pub fn context_properties() -> Vec<(String, String)> {
+    let context = tracing::Span::current().context();
+    let mut properties = ContextProperties(Vec::new());
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut properties)
+    });
+    properties.0
+}
+
+/// Reads the trace context from the user properties of a received message.
+struct UserProperties<'a>(&'a [(String, String)]);
+
+impl Extractor for UserProperties<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(name, _)| name.as_str()).collect()
+    }
+}
+
+/// Collects the trace context into user properties for a published message.
+struct ContextProperties(Vec<(String, String)>);
+
+impl Injector for ContextProperties {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.push((key.to_owned(), value));
+    }
+}
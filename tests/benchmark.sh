@@ -0,0 +1,139 @@
+#!/bin/bash
+
+set -euo pipefail
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+
+# Reuse the broker, plugin and cleanup helpers of the integration test
+source "$SCRIPT_DIR/integration_test.sh"
+
+BENCH_TOPIC="bench"
+BENCH_MESSAGES="${BENCH_MESSAGES:-10000}"
+BENCH_CONCURRENCY="${BENCH_CONCURRENCY:-8}"
+BENCH_TIMEOUT="${BENCH_TIMEOUT:-300}"
+BENCH_APP_DIR="$PROJECT_DIR/examples/mqtt-app"
+BENCH_MANIFEST="$BENCH_APP_DIR/spin.bench.toml"
+BENCH_LOG_DIR="$PROJECT_DIR/logs/bench"
+
+bench_cleanup() {
+    rm -f "$BENCH_MANIFEST"
+    cleanup
+}
+
+trap bench_cleanup EXIT
+
+write_bench_manifest() {
+    local reuse_instances="$1"
+
+    cat > "$BENCH_MANIFEST" <<EOF
+spin_manifest_version = 2
+
+[application]
+name = "mqtt-bench"
+version = "0.1.0"
+
+[application.trigger.mqtt]
+address = "mqtt://$MQTT_HOST:$MQTT_PORT"
+username = "$MQTT_USERNAME"
+password = "$MQTT_PASSWORD"
+keep_alive_interval = "30"
+
+[[trigger.mqtt]]
+component = "mqtt-bench"
+topic = "$BENCH_TOPIC"
+qos = "1"
+max_concurrency = $BENCH_CONCURRENCY
+reuse_instances = $reuse_instances
+
+[component.mqtt-bench]
+source = "target/wasm32-wasip2/release/mqtt_app.wasm"
+allowed_outbound_hosts = ["mqtt://$MQTT_HOST:$MQTT_PORT"]
+EOF
+}
+
+# Publish the messages and print the throughput of the component handling them
+run_bench() {
+    local reuse_instances="$1"
+    local bench_logs_stdout="$BENCH_LOG_DIR/mqtt-bench_stdout.txt"
+
+    log "Benchmarking with reuse_instances = $reuse_instances..."
+
+    write_bench_manifest "$reuse_instances"
+    rm -rf "$BENCH_LOG_DIR"
+    spin up --from "$BENCH_MANIFEST" --log-dir "$BENCH_LOG_DIR" &
+    SPIN_PID=$!
+    sleep 5
+
+    local start
+    start=$(date +%s.%N)
+    mqttx bench pub \
+        -t "$BENCH_TOPIC" \
+        -h "$MQTT_HOST" \
+        -p "$MQTT_PORT" \
+        -u "$MQTT_USERNAME" \
+        -P "$MQTT_PASSWORD" \
+        -q 1 \
+        -c 1 \
+        --message-interval 0 \
+        --limit "$BENCH_MESSAGES" \
+        -m "benchmark message" &>/dev/null &
+    local publisher_pid=$!
+
+    # Wait for the component to have handled every message
+    local handled=0
+    while [ "$handled" -lt "$BENCH_MESSAGES" ]; do
+        if [ "$(echo "$(date +%s.%N) - $start > $BENCH_TIMEOUT" | bc)" -eq 1 ]; then
+            error "Only $handled of $BENCH_MESSAGES messages were handled within ${BENCH_TIMEOUT}s"
+            exit 1
+        fi
+        sleep 0.1
+        handled=$(grep -c "Message received by wasm component" "$bench_logs_stdout" 2>/dev/null || true)
+        handled=${handled:-0}
+    done
+    local end
+    end=$(date +%s.%N)
+
+    kill "$publisher_pid" 2>/dev/null || true
+    kill "$SPIN_PID"
+    wait "$SPIN_PID" 2>/dev/null || true
+    SPIN_PID=""
+
+    local elapsed
+    elapsed=$(echo "$end - $start" | bc)
+    printf "reuse_instances = %-5s  %d messages in %.2fs  %.0f msg/s\n" \
+        "$reuse_instances" "$BENCH_MESSAGES" "$elapsed" \
+        "$(echo "$BENCH_MESSAGES / $elapsed" | bc -l)" | tee -a "$PROJECT_DIR/logs/bench.txt"
+}
+
+# Print the machine and settings the benchmark runs with, to report along with the results
+describe_machine() {
+    local cpu
+    cpu=$(grep -m1 "model name" /proc/cpuinfo 2>/dev/null | cut -d: -f2 | sed 's/^ *//' || true)
+    cpu=${cpu:-$(sysctl -n machdep.cpu.brand_string 2>/dev/null || uname -m)}
+    echo "Machine: $cpu, $(getconf _NPROCESSORS_ONLN) CPUs, $(uname -sr)"
+    echo "Settings: $BENCH_MESSAGES QoS 1 messages, max_concurrency = $BENCH_CONCURRENCY"
+}
+
+run_benchmark() {
+    log "Starting MQTT Trigger Plugin Benchmark"
+    log "======================================"
+
+    check_dependencies
+    start_mqtt_broker
+    build_and_install_plugin
+    spin build --from "$BENCH_APP_DIR/spin.toml"
+
+    mkdir -p "$PROJECT_DIR/logs"
+    rm -f "$PROJECT_DIR/logs/bench.txt"
+    describe_machine | tee "$PROJECT_DIR/logs/bench.txt"
+    run_bench false
+    run_bench true
+
+    log "======================================"
+    log "Benchmark completed, results in logs/bench.txt:"
+    cat "$PROJECT_DIR/logs/bench.txt"
+}
+
+if [[ "${BASH_SOURCE[0]}" == "${0}" ]]; then
+    run_benchmark
+fi
@@ -11,6 +11,8 @@ MQTT_USERNAME="admin"
 MQTT_PASSWORD="public"
 TEST_TOPIC="messages-in01"
 TEST_MESSAGE="Hello to MQTT Spin Component!"
+TEST_SHARED_TOPIC="messages-in02"
+TEST_SHARED_MESSAGE="Hello to shared MQTT Spin Component!"
 
 # Colors for output
 RED='\033[0;31m'
@@ -130,6 +132,7 @@ start_spin_app() {
     # Create log file for spin output (overwrite if exists)
     SPIN_LOG_DIR="$PROJECT_DIR/logs"
     SPIN_LOGS_STDOUT="$SPIN_LOG_DIR/mqtt-c01_stdout.txt"
+    SPIN_SHARED_LOGS_STDOUT="$SPIN_LOG_DIR/mqtt-c02_stdout.txt"
     
     # Build and start the example app in background, capturing output
     spin build --from examples/mqtt-app/spin.toml
@@ -192,9 +195,38 @@ test_mqtt_message_flow() {
         exit 1
     fi
 
+    log "MQTT message flow test completed successfully"
+}
+
+test_shared_subscription() {
+    log "Testing MQTT shared subscription..."
+    
+    log "Publishing test message to shared topic '$TEST_SHARED_TOPIC'..."
+    
+    mqttx pub \
+        -t "$TEST_SHARED_TOPIC" \
+        -h "$MQTT_HOST" \
+        -p "$MQTT_PORT" \
+        -u "$MQTT_USERNAME" \
+        -P "$MQTT_PASSWORD" \
+        -m "$TEST_SHARED_MESSAGE"
+    
+    sleep 5
+    
+    # The component must see the real publish topic, not the '$share/...' filter
+    log "Checking if the shared subscription received the message on the publish topic..."
+    if grep -q "'$TEST_SHARED_MESSAGE' on topic '$TEST_SHARED_TOPIC'" "$SPIN_SHARED_LOGS_STDOUT"; then
+        log "✅ SUCCESS: Shared subscription message found with topic '$TEST_SHARED_TOPIC'!"
+    else
+        error "❌ FAILURE: Shared subscription message not found with topic '$TEST_SHARED_TOPIC'"
+        log "Full Spin output:"
+        cat "$SPIN_SHARED_LOGS_STDOUT"
+        exit 1
+    fi
+
     rm -rf "$SPIN_LOG_DIR" || true
     
-    log "MQTT message flow test completed successfully"
+    log "MQTT shared subscription test completed successfully"
 }
 
 run_integration_test() {
@@ -206,6 +238,7 @@ run_integration_test() {
     build_and_install_plugin
     start_spin_app
     test_mqtt_message_flow
+    test_shared_subscription
     
     log "=============================================="
     log "Integration test completed successfully!"